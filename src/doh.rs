@@ -0,0 +1,284 @@
+use std::{
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use clap::ValueEnum;
+use h2::client::SendRequest;
+use http::{header, Method, Request};
+use rustls::{pki_types::ServerName, ClientConfig, HandshakeKind};
+use tokio::{net::TcpSocket, runtime::Runtime};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    tls::Tls,
+    transport::{Conn, Local, Setup},
+    Args,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum DohMethod {
+    /// Query in the `dns` URL parameter, base64url encoded
+    Get,
+    /// Query as an `application/dns-message` request body
+    Post,
+}
+
+/// A DoH request that produced no DNS message
+#[derive(Debug)]
+pub struct HttpFailure {
+    pub id: u16,
+    /// Status of a non-200 response, or None if the request itself failed
+    pub status: Option<u16>,
+}
+
+impl fmt::Display for HttpFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "query {} got HTTP status {}", self.id, status),
+            None => write!(f, "query {} failed", self.id),
+        }
+    }
+}
+
+impl Error for HttpFailure {}
+
+/// The `HttpFailure` behind a receive error, if there is one
+pub fn failure(e: &io::Error) -> Option<&HttpFailure> {
+    e.get_ref()?.downcast_ref()
+}
+
+/// HTTP/2 runs on one small runtime shared by all DoH connections; the
+/// worker threads only hand requests to it and wait for the answers.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap()
+    })
+}
+
+type Reply = Result<Vec<u8>, HttpFailure>;
+
+/// The current HTTP/2 connection of a worker, see `transport::Slot`
+struct Slot {
+    generation: u64,
+    sender: Option<SendRequest<Bytes>>,
+}
+
+/// DNS over HTTPS (RFC 8484). Requests are multiplexed as HTTP/2 streams
+/// on one connection, and their answers are queued in arrival order.
+pub struct Doh {
+    server: SocketAddr,
+    local: Local,
+    timeout: Duration,
+    config: Arc<ClientConfig>,
+    name: ServerName<'static>,
+    method: DohMethod,
+    uri: String,
+    shared: Arc<Mutex<Slot>>,
+    replies: Arc<(Sender<Reply>, Mutex<Receiver<Reply>>)>,
+}
+
+impl Doh {
+    pub fn new(args: &Args, tls: &Tls, local: Local) -> Self {
+        let authority = match &args.tls_name {
+            Some(name) => format!("{}:{}", name, args.server.port()),
+            None => args.server.to_string(),
+        };
+        let (tx, rx) = channel();
+        Doh {
+            server: args.server,
+            local,
+            timeout: Duration::from_millis(args.timeout),
+            config: tls.config.clone(),
+            name: tls.name.clone(),
+            method: args.doh_method,
+            uri: format!("https://{}{}", authority, args.doh_path),
+            shared: Arc::new(Mutex::new(Slot {
+                generation: 0,
+                sender: None,
+            })),
+            replies: Arc::new((tx, Mutex::new(rx))),
+        }
+    }
+
+    async fn establish(&self, generation: u64) -> io::Result<(SendRequest<Bytes>, Setup)> {
+        let start = Instant::now();
+        let socket = self.local.bind(|addr| {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            socket.bind(addr)?;
+            Ok(socket)
+        })?;
+        let tcp = socket.connect(self.server).await?;
+        tcp.set_nodelay(true)?;
+        let tls = TlsConnector::from(self.config.clone())
+            .connect(self.name.clone(), tcp)
+            .await?;
+        let session = tls.get_ref().1;
+        if session.alpn_protocol() != Some(b"h2") {
+            return Err(io::Error::other("server did not negotiate HTTP/2"));
+        }
+        let resumed = session.handshake_kind() == Some(HandshakeKind::Resumed);
+
+        let (sender, connection) = h2::client::handshake(tls).await.map_err(io::Error::other)?;
+        let shared = self.shared.clone();
+        runtime().spawn(async move {
+            let _ = connection.await;
+            // Requests still open on it fail on their own; just make sure
+            // the next query reconnects
+            let mut slot = shared.lock().unwrap();
+            if slot.generation == generation {
+                slot.sender = None;
+            }
+        });
+        let setup = Setup {
+            time: start.elapsed(),
+            resumed,
+        };
+        Ok((sender, setup))
+    }
+
+    fn request(&self, msg: &[u8]) -> Request<()> {
+        let builder = match self.method {
+            DohMethod::Get => Request::builder().method(Method::GET).uri(format!(
+                "{}?dns={}",
+                self.uri,
+                URL_SAFE_NO_PAD.encode(msg)
+            )),
+            DohMethod::Post => Request::builder()
+                .method(Method::POST)
+                .uri(&self.uri)
+                .header(header::CONTENT_TYPE, "application/dns-message")
+                .header(header::CONTENT_LENGTH, msg.len()),
+        };
+        builder
+            .header(header::ACCEPT, "application/dns-message")
+            .body(())
+            .unwrap()
+    }
+}
+
+impl Conn for Doh {
+    fn connect(&mut self) -> io::Result<Option<Setup>> {
+        let mut slot = self.shared.lock().unwrap();
+        if slot.sender.is_some() {
+            return Ok(None);
+        }
+        let generation = slot.generation + 1;
+        let (sender, setup) = runtime().block_on(async {
+            tokio::time::timeout(self.timeout, self.establish(generation))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        })?;
+        slot.generation = generation;
+        slot.sender = Some(sender);
+        Ok(Some(setup))
+    }
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let (generation, sender) = {
+            let slot = self.shared.lock().unwrap();
+            match &slot.sender {
+                Some(sender) => (slot.generation, sender.clone()),
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            }
+        };
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        let request = self.request(msg);
+        let body = (self.method == DohMethod::Post).then(|| Bytes::copy_from_slice(msg));
+
+        let response = runtime().block_on(async {
+            let mut sender = sender.ready().await?;
+            let (response, mut stream) = sender.send_request(request, body.is_none())?;
+            if let Some(body) = body {
+                stream.send_data(body, true)?;
+            }
+            Ok::<_, h2::Error>(response)
+        });
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                let mut slot = self.shared.lock().unwrap();
+                if slot.generation == generation {
+                    slot.sender = None;
+                }
+                return Err(io::Error::other(e));
+            }
+        };
+
+        let replies = self.replies.clone();
+        let timeout = self.timeout;
+        runtime().spawn(async move {
+            let reply = async {
+                let response = response.await.ok()?;
+                if response.status() != http::StatusCode::OK {
+                    return Some(Err(response.status().as_u16()));
+                }
+                let mut body = response.into_body();
+                let mut answer = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.ok()?;
+                    let _ = body.flow_control().release_capacity(chunk.len());
+                    answer.extend_from_slice(&chunk);
+                }
+                Some(Ok(answer))
+            };
+            let reply = match tokio::time::timeout(timeout, reply).await {
+                // Nobody waits for it any more
+                Err(_) => return,
+                Ok(Some(Ok(answer))) => Ok(answer),
+                Ok(Some(Err(status))) => Err(HttpFailure {
+                    id,
+                    status: Some(status),
+                }),
+                Ok(None) => Err(HttpFailure { id, status: None }),
+            };
+            let _ = replies.0.send(reply);
+        });
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match self.replies.1.lock().unwrap().recv_timeout(left) {
+            Ok(Ok(answer)) => {
+                let n = answer.len().min(buf.len());
+                buf[..n].copy_from_slice(&answer[..n]);
+                Ok(n)
+            }
+            Ok(Err(failure)) => Err(io::Error::other(failure)),
+            Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(Doh {
+            server: self.server,
+            local: self.local.clone(),
+            timeout: self.timeout,
+            config: self.config.clone(),
+            name: self.name.clone(),
+            method: self.method,
+            uri: self.uri.clone(),
+            shared: self.shared.clone(),
+            replies: self.replies.clone(),
+        }))
+    }
+}
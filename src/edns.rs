@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+
+use rand::Rng;
+
+use crate::{message::Message, Args};
+
+const NSID: u16 = 3;
+const CLIENT_SUBNET: u16 = 8;
+const COOKIE: u16 = 10;
+const PADDING: u16 = 12;
+
+/// The EDNS(0) OPT record added to every query (RFC 6891)
+#[derive(Clone, Debug)]
+pub struct Edns {
+    bufsize: u16,
+    dnssec: bool,
+    ecs: Option<(IpAddr, u8)>,
+    /// Client cookie (RFC 7873), one per worker like one per client
+    cookie: Option<[u8; 8]>,
+    nsid: bool,
+    /// Block size to pad queries to (RFC 7830, RFC 8467)
+    padding: Option<u16>,
+}
+
+/// Which parts of the OPT record came back in a response
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Echo {
+    pub opt: bool,
+    pub dnssec: bool,
+    pub ecs: bool,
+    pub cookie: bool,
+    pub nsid: bool,
+    pub padding: bool,
+}
+
+impl Edns {
+    /// None unless one of the EDNS options is given
+    pub fn new(args: &Args) -> Option<Self> {
+        enabled(args).then(|| Edns {
+            bufsize: args.bufsize.unwrap_or(1232),
+            dnssec: args.dnssec,
+            ecs: args.ecs,
+            cookie: args.cookie.then(|| rand::thread_rng().gen()),
+            nsid: args.nsid,
+            padding: args.padding,
+        })
+    }
+
+    /// Appends the OPT record to an encoded query without additional records
+    pub fn append(&self, packet: &mut Vec<u8>) {
+        packet[10..12].copy_from_slice(&1u16.to_be_bytes());
+
+        let mut options = Vec::new();
+        if self.nsid {
+            option(&mut options, NSID, &[]);
+        }
+        if let Some((ip, prefix)) = self.ecs {
+            let (family, octets) = match ip {
+                IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (2u16, ip.octets().to_vec()),
+            };
+            let mut data = family.to_be_bytes().to_vec();
+            data.extend_from_slice(&[prefix, 0]);
+            let mut address = octets[..(prefix as usize).div_ceil(8)].to_vec();
+            if let (Some(last), 1..) = (address.last_mut(), prefix % 8) {
+                // Bits past the prefix must be zero
+                *last &= 0xff << (8 - prefix % 8);
+            }
+            data.extend_from_slice(&address);
+            option(&mut options, CLIENT_SUBNET, &data);
+        }
+        if let Some(cookie) = self.cookie {
+            option(&mut options, COOKIE, &cookie);
+        }
+        if let Some(block) = self.padding {
+            // Root name, type, class, TTL and RDLENGTH, then the padding
+            // option's own code and length
+            let len = packet.len() + 11 + options.len() + 4;
+            let pad = (block as usize - len % block as usize) % block as usize;
+            option(&mut options, PADDING, &vec![0; pad]);
+        }
+
+        packet.push(0);
+        packet.extend_from_slice(&41u16.to_be_bytes());
+        packet.extend_from_slice(&self.bufsize.to_be_bytes());
+        let flags: u16 = if self.dnssec { 0x8000 } else { 0 };
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&options);
+    }
+
+    pub fn echo(&self, msg: &Message) -> Echo {
+        let Some(opt) = &msg.opt else {
+            return Echo::default();
+        };
+        Echo {
+            opt: true,
+            dnssec: opt.dnssec_ok,
+            ecs: opt.options.contains(&CLIENT_SUBNET),
+            cookie: opt.options.contains(&COOKIE),
+            nsid: opt.options.contains(&NSID),
+            padding: opt.options.contains(&PADDING),
+        }
+    }
+}
+
+pub fn enabled(args: &Args) -> bool {
+    args.edns
+        || args.bufsize.is_some()
+        || args.dnssec
+        || args.ecs.is_some()
+        || args.cookie
+        || args.nsid
+        || args.padding.is_some()
+}
+
+fn option(options: &mut Vec<u8>, code: u16, data: &[u8]) {
+    options.extend_from_slice(&code.to_be_bytes());
+    options.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    options.extend_from_slice(data);
+}
+
+/// Parses a client subnet such as `192.0.2.0/24`; a bare address is a /32
+/// or /128
+pub fn parse_subnet(s: &str) -> Result<(IpAddr, u8), String> {
+    let invalid = || format!("invalid subnet `{}`", s);
+    let (ip, prefix) = match s.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (s, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+        None => max,
+    };
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok((ip, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnets() {
+        let v4: IpAddr = "192.0.2.0".parse().unwrap();
+        let v6: IpAddr = "2001:db8::".parse().unwrap();
+        assert_eq!(parse_subnet("192.0.2.0/24"), Ok((v4, 24)));
+        assert_eq!(parse_subnet("192.0.2.0"), Ok((v4, 32)));
+        assert_eq!(parse_subnet("192.0.2.0/0"), Ok((v4, 0)));
+        assert_eq!(parse_subnet("2001:db8::/56"), Ok((v6, 56)));
+        assert_eq!(parse_subnet("2001:db8::"), Ok((v6, 128)));
+        for bad in [
+            "",
+            "/24",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2/24",
+            "192.0.2.0/",
+            "192.0.2.0/-1",
+        ] {
+            assert!(parse_subnet(bad).is_err(), "{}", bad);
+        }
+    }
+}
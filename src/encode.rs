@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use rand::Rng;
+
+use crate::{edns::Edns, message::QType, mix::Mix, Args};
+
+/// How the letters of query names are written
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Case {
+    /// As given in the domain list or replay file
+    Keep,
+    Lower,
+    Upper,
+    /// Each letter upper or lower case at random, as in DNS 0x20
+    Random,
+}
+
+/// Writes queries in wire format: one question with the RD bit set, names
+/// never compressed, and the OPT record last if EDNS is on
+#[derive(Clone)]
+pub struct Encoder {
+    pub case: Case,
+    pub qclass: u16,
+    pub edns: Option<Edns>,
+    /// The queries of the random workload, encoded ahead
+    pub packets: Option<Arc<Packets>>,
+}
+
+impl Encoder {
+    pub fn new(args: &Args) -> Encoder {
+        Encoder {
+            case: args.case,
+            qclass: args.class,
+            edns: Edns::new(args),
+            packets: None,
+        }
+    }
+
+    pub fn encode(&self, id: u16, qname: &str, qtype: QType, rng: &mut impl Rng) -> Vec<u8> {
+        let mut packet = Vec::new();
+        self.write(&mut packet, id, qname, qtype, None, rng);
+        packet
+    }
+
+    /// Writes the query into `packet`, replacing what was there. A query
+    /// with an `index` into the pre-encoded packets is copied from there
+    /// with only its ID patched in, so the buffer is not reallocated once it
+    /// has grown to the largest query.
+    ///
+    /// Panics on a name with an empty label or one longer than 63 bytes,
+    /// or a name longer than 255 bytes in wire format
+    pub fn write(
+        &self,
+        packet: &mut Vec<u8>,
+        id: u16,
+        qname: &str,
+        qtype: QType,
+        index: Option<usize>,
+        rng: &mut impl Rng,
+    ) {
+        packet.clear();
+        if let (Some(packets), Some(index)) = (&self.packets, index) {
+            packet.extend_from_slice(packets.get(index));
+            packet[..2].copy_from_slice(&id.to_be_bytes());
+            return;
+        }
+        packet.extend_from_slice(&id.to_be_bytes());
+        // Standard query with RD
+        packet.extend_from_slice(&0x0100u16.to_be_bytes());
+        // QDCOUNT 1; ANCOUNT, NSCOUNT and ARCOUNT 0 until the OPT is added
+        packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let name = qname.strip_suffix('.').unwrap_or(qname);
+        if !name.is_empty() {
+            for label in name.split('.') {
+                assert!(
+                    (1..=63).contains(&label.len()),
+                    "bad label `{}` in name `{}`",
+                    label,
+                    qname
+                );
+                packet.push(label.len() as u8);
+                packet.extend(label.bytes().map(|byte| match self.case {
+                    Case::Keep => byte,
+                    Case::Lower => byte.to_ascii_lowercase(),
+                    Case::Upper => byte.to_ascii_uppercase(),
+                    Case::Random if rng.gen() => byte.to_ascii_uppercase(),
+                    Case::Random => byte.to_ascii_lowercase(),
+                }));
+            }
+        }
+        packet.push(0);
+        assert!(packet.len() - 12 <= 255, "name `{}` is too long", qname);
+
+        packet.extend_from_slice(&qtype.0.to_be_bytes());
+        packet.extend_from_slice(&self.qclass.to_be_bytes());
+        if let Some(edns) = &self.edns {
+            edns.append(packet);
+        }
+    }
+}
+
+/// A query for every domain and every type of the --record mix, encoded
+/// once at startup. The query for domain `d` and the mix's type `t` is
+/// number `d * types + t`.
+pub struct Packets {
+    bytes: Vec<u8>,
+    /// Where each packet ends in `bytes`
+    ends: Vec<usize>,
+}
+
+impl Packets {
+    /// None if queries differ between sends or workers: with --case random
+    /// and with --cookie, whose client cookie is per worker
+    pub fn new(args: &Args, domains: &[String], mix: &Mix) -> Option<Packets> {
+        if args.case == Case::Random || args.cookie {
+            return None;
+        }
+        let encoder = Encoder::new(args);
+        let mut rng = rand::thread_rng();
+        let mut packets = Packets {
+            bytes: Vec::new(),
+            ends: Vec::with_capacity(domains.len() * mix.types.len()),
+        };
+        let mut packet = Vec::new();
+        for domain in domains {
+            for &qtype in &mix.types {
+                encoder.write(&mut packet, 0, domain, qtype, None, &mut rng);
+                packets.bytes.extend_from_slice(&packet);
+                packets.ends.push(packets.bytes.len());
+            }
+        }
+        Some(packets)
+    }
+
+    fn get(&self, index: usize) -> &[u8] {
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i]);
+        &self.bytes[start..self.ends[index]]
+    }
+}
+
+/// Parses a class mnemonic such as `IN` or `CH`, or the RFC 3597 `CLASSnnn`
+/// form
+pub fn parse_class(s: &str) -> Result<u16, String> {
+    let upper = s.to_ascii_uppercase();
+    match upper.as_str() {
+        "IN" => Ok(1),
+        "CH" => Ok(3),
+        "HS" => Ok(4),
+        "NONE" => Ok(254),
+        "ANY" => Ok(255),
+        _ => upper
+            .strip_prefix("CLASS")
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("unknown class `{}`", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn encoder(options: &[&str]) -> Encoder {
+        Encoder::new(&Args::parse_from(["dnsbench"].iter().chain(options)))
+    }
+
+    fn encode(options: &[&str], qname: &str, qtype: u16) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(1);
+        encoder(options).encode(0x1234, qname, QType(qtype), &mut rng)
+    }
+
+    /// Header with ID 0x1234, RD, one question and `arcount` additional
+    /// records, then the question for example.com
+    fn query(arcount: u8, qtype: [u8; 2], qclass: [u8; 2]) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, arcount];
+        packet.extend_from_slice(b"\x07example\x03com\x00");
+        packet.extend_from_slice(&qtype);
+        packet.extend_from_slice(&qclass);
+        packet
+    }
+
+    /// The letters of the name of an encoded query
+    fn name(packet: &[u8]) -> String {
+        let end = packet.len() - 4;
+        String::from_utf8_lossy(&packet[12..end]).into_owned()
+    }
+
+    #[test]
+    fn a() {
+        assert_eq!(encode(&[], "example.com", 1), query(0, [0, 1], [0, 1]));
+        // A trailing dot makes no difference
+        assert_eq!(encode(&[], "example.com.", 1), query(0, [0, 1], [0, 1]));
+    }
+
+    #[test]
+    fn types_above_255() {
+        assert_eq!(encode(&[], "example.com", 257), query(0, [1, 1], [0, 1]));
+        assert_eq!(
+            encode(&[], "example.com", 65534),
+            query(0, [0xff, 0xfe], [0, 1])
+        );
+    }
+
+    #[test]
+    fn class() {
+        let packet = encode(&["--class", "CH"], "example.com", 16);
+        assert_eq!(packet, query(0, [0, 16], [0, 3]));
+        let packet = encode(&["--class", "CLASS65280"], "example.com", 1);
+        assert_eq!(packet, query(0, [0, 1], [0xff, 0]));
+    }
+
+    #[test]
+    fn case() {
+        let qname = "ExAmple.COM";
+        let keep = name(&encode(&["--case", "keep"], qname, 1));
+        assert_eq!(keep, "\x07ExAmple\x03COM\x00");
+        let lower = name(&encode(&["--case", "lower"], qname, 1));
+        assert_eq!(lower, "\x07example\x03com\x00");
+        let upper = name(&encode(&["--case", "upper"], qname, 1));
+        assert_eq!(upper, "\x07EXAMPLE\x03COM\x00");
+
+        let long = "abcdefghijklmnopqrstuvwxyz.example.com";
+        let random = name(&encode(&["--case", "random"], long, 1));
+        assert_eq!(random.to_ascii_lowercase(), name(&encode(&[], long, 1)));
+        assert!(random.bytes().any(|byte| byte.is_ascii_uppercase()));
+        assert!(random.bytes().any(|byte| byte.is_ascii_lowercase()));
+    }
+
+    #[test]
+    fn root() {
+        let packet = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1];
+        assert_eq!(encode(&[], ".", 2), packet);
+        assert_eq!(encode(&[], "", 2), packet);
+    }
+
+    #[test]
+    fn opt_with_client_subnet() {
+        let mut packet = query(1, [0, 1], [0, 1]);
+        // Root, OPT, 1232 bytes, no extended RCODE or flags
+        packet.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0]);
+        // One option: ECS for IPv4, source prefix 24, scope 0, 192.0.2
+        packet.extend_from_slice(&[0, 11, 0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]);
+        assert_eq!(encode(&["--ecs", "192.0.2.1/24"], "example.com", 1), packet);
+    }
+
+    #[test]
+    fn opt_with_dnssec_and_padding() {
+        let packet = encode(
+            &["--dnssec", "--bufsize", "4096", "--padding", "128"],
+            "example.com",
+            1,
+        );
+        assert_eq!(packet.len(), 128);
+        let opt = query(1, [0, 1], [0, 1]).len();
+        assert_eq!(&packet[opt..opt + 9], &[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0]);
+        // The padding option takes up the rest, with zeros
+        let pad = packet.len() - opt - 15;
+        assert_eq!(&packet[opt + 11..opt + 15], &[0, 12, 0, pad as u8]);
+        assert!(packet[opt + 15..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn longest_label_and_name() {
+        let label = "a".repeat(63);
+        let packet = encode(&[], &format!("{}.com", label), 1);
+        assert_eq!(packet[12], 63);
+        // 3 * 64 + 62 + 1 = 255 bytes in wire format
+        let name = format!("{0}.{0}.{0}.{1}", label, "a".repeat(61));
+        assert_eq!(encode(&[], &name, 1).len(), 12 + 255 + 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad label")]
+    fn label_too_long() {
+        encode(&[], &format!("{}.com", "a".repeat(64)), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad label")]
+    fn empty_label() {
+        encode(&[], "example..com", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "too long")]
+    fn name_too_long() {
+        let label = "a".repeat(63);
+        encode(&[], &format!("{0}.{0}.{0}.{1}", label, "a".repeat(62)), 1);
+    }
+
+    #[test]
+    fn packets() {
+        let args = Args::parse_from(["dnsbench", "--record", "A=60,CAA=40", "--nsid"]);
+        let domains = ["example.com".to_string(), "example.net".to_string()];
+        let packets = Packets::new(&args, &domains, &args.record).unwrap();
+        let cached = Encoder {
+            packets: Some(Arc::new(packets)),
+            ..Encoder::new(&args)
+        };
+        let fresh = Encoder::new(&args);
+        let mut rng = rand::thread_rng();
+        let (mut from_cache, mut encoded) = (Vec::new(), Vec::new());
+        for (d, domain) in domains.iter().enumerate() {
+            for (t, &qtype) in args.record.types.iter().enumerate() {
+                let index = d * args.record.types.len() + t;
+                let id = 1000 + index as u16;
+                cached.write(&mut from_cache, id, domain, qtype, Some(index), &mut rng);
+                fresh.write(&mut encoded, id, domain, qtype, None, &mut rng);
+                assert_eq!(from_cache, encoded);
+            }
+        }
+    }
+
+    #[test]
+    fn packets_not_shared() {
+        let random = Args::parse_from(["dnsbench", "--case", "random"]);
+        assert!(Packets::new(&random, &[], &random.record).is_none());
+        let cookie = Args::parse_from(["dnsbench", "--cookie"]);
+        assert!(Packets::new(&cookie, &[], &cookie.record).is_none());
+    }
+}
@@ -0,0 +1,31 @@
+use rand::Rng;
+
+/// Transaction IDs for the queries of one socket or connection. IDs wrap
+/// around after 65535, and the caller says which ones are still in flight
+/// so they are never handed out twice at the same time.
+pub struct Ids {
+    random: bool,
+    next: u16,
+}
+
+impl Ids {
+    pub fn new(random: bool) -> Self {
+        Ids { random, next: 0 }
+    }
+
+    /// There must be at least one ID that is not in use
+    pub fn next(&mut self, rng: &mut impl Rng, in_use: impl Fn(u16) -> bool) -> u16 {
+        loop {
+            let id = if self.random {
+                rng.gen()
+            } else {
+                let id = self.next;
+                self.next = self.next.wrapping_add(1);
+                id
+            };
+            if !in_use(id) {
+                return id;
+            }
+        }
+    }
+}
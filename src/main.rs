@@ -1,104 +1,397 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    net::{SocketAddr, UdpSocket},
-    sync::{mpsc::channel, Arc, Mutex},
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
     thread::{self, ThreadId},
-    time::SystemTime,
+    time::{Duration, Instant},
 };
 
 use addr::parse_domain_name;
-use clap::Parser;
-use dns_parser::{QueryClass, QueryType};
-use rand::seq::SliceRandom;
+use clap::{CommandFactory, Parser, Subcommand};
+use doh::{DohMethod, HttpFailure};
+use edns::Echo;
+use encode::{Case, Encoder, Packets};
+use ids::Ids;
+use message::{Message, QType, Rcode};
+use mix::Mix;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rate::{Arrival, Pacer};
+use report::Output;
+use serde_json::{json, Value};
+use serve::ServeArgs;
+use stats::{Cpu, Totals};
+use trace::Trace;
+use transport::{Conn, Io, Local, Setup, Transport};
+use validate::{Check, Validator};
+use workload::{Source, Workload};
+
+mod doh;
+mod edns;
+mod encode;
+mod ids;
+mod message;
+mod mix;
+#[cfg(target_os = "linux")]
+mod mmsg;
+mod pipeline;
+mod rate;
+mod report;
+mod serve;
+mod stats;
+mod tls;
+mod trace;
+mod transport;
+#[cfg(target_os = "linux")]
+mod uring;
+mod validate;
+mod workload;
 
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(Command::Serve(serve)) = &args.command {
+        serve::run(serve);
+        return;
+    }
+    let mut servers = args.servers.clone();
+    if let Some(file) = &args.server_file {
+        match read_servers(file) {
+            Ok(read) => servers.extend(read),
+            Err(e) => Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit(),
+        }
+    }
+    if servers.is_empty() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "at least one --server or a --server-file is required",
+            )
+            .exit();
+    }
+    if args.io == Io::Uring
+        && !(cfg!(target_os = "linux") && matches!(args.transport, Transport::Udp | Transport::Tcp))
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--io uring needs Linux and the udp or tcp transport",
+            )
+            .exit();
+    }
+    if args.batch > 1
+        && (args.inflight == 1 || args.io == Io::Uring || args.transport != Transport::Udp)
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--batch only applies to the udp transport with --inflight above 1 and --io threads",
+            )
+            .exit();
+    }
+    args.seed.get_or_insert_with(rand::random);
+
+    let mut trace = args
+        .trace_file
+        .as_ref()
+        .map(|file| BufWriter::new(File::create(file).unwrap()));
+    // Progress must not end up inside a json or csv document on stdout
+    let to_stderr = args.output != Output::Text && args.output_file.is_none();
 
-    let domains = Arc::new(read_domains(&args.domains));
+    let mut runs = Vec::new();
+    for &server in &servers {
+        if servers.len() > 1 {
+            progress(&format!("SERVER {}", server), to_stderr);
+        }
+        let run = run(
+            Args {
+                server,
+                ..args.clone()
+            },
+            &mut trace,
+            to_stderr,
+        );
+        if !to_stderr {
+            report::text(&run);
+        }
+        runs.push(run);
+    }
+    if let Some(mut out) = trace {
+        out.flush().unwrap();
+    }
+    if runs.len() > 1 && !to_stderr {
+        report::compare(&runs);
+    }
+
+    if args.output == Output::Text {
+        return;
+    }
+    let docs: Vec<Value> = runs.iter().map(report::json).collect();
+    let doc = match args.output {
+        Output::Csv => report::csv(&docs),
+        _ => {
+            let doc = match docs.len() {
+                1 => docs[0].clone(),
+                _ => json!({ "servers": docs }),
+            };
+            serde_json::to_string_pretty(&doc).unwrap() + "\n"
+        }
+    };
+    match &args.output_file {
+        Some(file) => fs::write(file, doc).unwrap(),
+        None => print!("{}", doc),
+    }
+}
+
+/// Everything measured against one server
+struct Run {
+    args: Args,
+    totals: Totals,
+    elapsed: Duration,
+    /// CPU time the client used over `elapsed`
+    cpu: Cpu,
+    intervals: Vec<Value>,
+}
+
+fn run(args: Args, trace: &mut Option<BufWriter<File>>, to_stderr: bool) -> Run {
+    let mut replay = args.replay.as_ref().map(|file| {
+        workload::replay(file, args.threads, args.replay_server).unwrap_or_else(|e| {
+            Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit()
+        })
+    });
+    let domains = match replay {
+        Some(_) => Arc::new(Vec::new()),
+        None => Arc::new(read_domains(&args.domains)),
+    };
+    let packets = Packets::new(&args, &domains, &args.record).map(Arc::new);
 
     let (tx, rx) = channel();
     let mut threads = Vec::new();
 
-    let now = SystemTime::now();
-
-    let id = Arc::new(Mutex::new(0u16));
-    for _ in 0..args.threads {
-        let tx = tx.clone();
-        let server = args.server;
-        let number = args.number;
-        let id = id.clone();
-        let domains = domains.clone();
-        let query_type = match args.record.as_str() {
-            "A" => QueryType::A,
-            "AAAA" => QueryType::AAAA,
-            _ => panic!("Invalid query type"),
+    let began = Instant::now();
+    let measured = began + args.warmup.unwrap_or_default();
+    let until = args.duration.map(|duration| measured + duration);
+
+    let tls = matches!(args.transport, Transport::Tls | Transport::Doh).then(|| tls::config(&args));
+
+    let validator = Validator::new(&args, &tx).map(Arc::new);
+    // The thread that asks the --reference server finishes last
+    let checkers = args.reference.is_some() as u32;
+
+    for i in 0..args.threads {
+        let tracing = args.trace_file.is_some();
+        let local = Local::new(&args, i);
+        let handler = Handler {
+            worker: i,
+            tracing,
+            debug: args.debug,
+            to_stderr,
+            timeout: Duration::from_millis(args.timeout),
+            encoder: Encoder {
+                packets: packets.clone(),
+                ..Encoder::new(&args)
+            },
+            validator: validator.clone(),
+            fallback: (args.tcp_fallback && args.transport == Transport::Udp)
+                .then_some((args.server, local.clone())),
+            tx: tx.clone(),
+        };
+        let source = match replay.as_mut() {
+            Some(replay) => Source::Replay {
+                queries: std::mem::take(&mut replay[i as usize]),
+                next: 0,
+                start: began,
+            },
+            None => Source::Random {
+                domains: domains.clone(),
+                mix: args.record.clone(),
+                pacer: args.qps.map(|qps| {
+                    Pacer::new(
+                        qps / args.threads as f64,
+                        args.arrival,
+                        i as f64 / args.threads as f64,
+                    )
+                }),
+                left: args.duration.is_none().then_some(args.number),
+            },
+        };
+        let mut workload = Workload {
+            source,
+            until,
+            rng: StdRng::seed_from_u64(args.seed.unwrap().wrapping_add(i as u64)),
         };
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        socket.connect(server).unwrap();
+        #[cfg(target_os = "linux")]
+        if args.io == Io::Uring {
+            threads.push(uring::spawn(&args, local, workload, handler));
+            continue;
+        }
+        let mut conn = transport::open(&args, tls.as_ref(), local);
+        if args.inflight > 1 {
+            let handles = pipeline::spawn(conn, &args, workload, handler);
+            threads.extend(handles);
+            continue;
+        }
         threads.push(std::thread::spawn(move || {
             let mut rng = rand::thread_rng();
-            for _ in 0..number {
-                let mut id = id.lock().unwrap();
-                let rid = *id;
-                *id += 1;
-                drop(id);
+            let mut ids = Ids::new(args.random_ids);
+            let mut packet = vec![0; 65535];
+            let mut wire = Vec::new();
+            let tx = &handler.tx;
+            while let Some((start, qname, query_type, index)) = workload.next() {
+                // Only one query is in flight at a time
+                let rid = ids.next(&mut rng, |_| false);
 
-                let qname = domains.choose(&mut rng).unwrap();
                 if args.debug >= 2 {
-                    println!("select domain: {}", qname);
+                    let line = format!("select domain: {} {}", qname, query_type);
+                    progress(&line, handler.to_stderr);
                 }
-                let (status, tid) = send_req(&socket, rid, qname, query_type);
-                tx.send((status.clone(), tid)).unwrap();
-                if status == WorkerStatus::Sent {
-                    let (status, tid) = recv_resp(&socket, rid, args.timeout, args.debug);
-                    tx.send((status, tid)).unwrap();
+                if !connect(conn.as_mut(), tx) {
+                    continue;
                 }
+                let query = Query {
+                    id: rid,
+                    qname,
+                    qtype: query_type,
+                    index,
+                    start,
+                };
+                let encoder = &handler.encoder;
+                let (status, tid) = send_req(conn.as_mut(), &query, encoder, &mut wire, &mut rng);
+                let outcome = match status {
+                    WorkerStatus::Sent(_) => {
+                        tx.send((status, tid)).unwrap();
+                        let deadline = Instant::now() + handler.timeout;
+                        recv_resp(conn.as_mut(), &query, deadline, &handler, &mut packet)
+                    }
+                    status => status,
+                };
+                let outcome = trace::wrap(tracing, i, rid, qname, start, outcome);
+                tx.send((outcome, tid)).unwrap();
             }
             tx.send((WorkerStatus::AllFinished, thread::current().id()))
                 .unwrap();
         }));
     }
+    // Lets the --reference thread finish once the workers have
+    drop(validator);
 
-    let mut sent = 0;
-    let mut success = 0;
-    let mut timeout = 0;
-    let mut failed = 0;
+    let every = args
+        .interval
+        .or((args.debug >= 1).then_some(1.0))
+        .map(Duration::from_secs_f64);
+    let mut intervals = Vec::new();
+    let mut window = Totals::new();
+    let mut window_start = began;
+    let mut totals = Totals::new();
+    let mut warming_up = args.warmup.is_some();
+    let mut cpu = Cpu::now();
     let mut all_finished = 0;
+    // When the last worker finished, and the CPU time it took to get there
+    let mut ended = None;
+    // Reasons connections failed for, each shown once
+    let mut connect_errors = HashSet::new();
     loop {
-        let (status, tid) = rx.recv().unwrap();
-        match status {
-            WorkerStatus::Sent => sent += 1,
-            WorkerStatus::Success => success += 1,
-            WorkerStatus::Timeout => timeout += 1,
-            WorkerStatus::Failed => failed += 1,
-            WorkerStatus::AllFinished => all_finished += 1,
-        }
-        let percent = (100.0 * sent as f64 / (args.threads * args.number) as f64) as u32;
-        if args.debug >= 1 {
-            println!(
-                "{:?} sent: {}, success: {}, timeout: {}, failed: {}, thread finished: {}, percent: {}%, time: {}s",
-                tid, sent, success, timeout, failed, all_finished, percent,now.elapsed().unwrap().as_secs_f32()
-            );
-        }
-        if all_finished == args.threads {
+        let received = match every {
+            Some(every) => {
+                let left = every.saturating_sub(window_start.elapsed());
+                match rx.recv_timeout(left) {
+                    Ok(received) => Some(received),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => unreachable!(),
+                }
+            }
+            None => Some(rx.recv().unwrap()),
+        };
+        if warming_up && Instant::now() >= measured {
+            // Only queries in flight across the boundary may still skew the
+            // counts
+            totals = Totals::new();
+            cpu = Cpu::now();
+            warming_up = false;
+        }
+        if let Some((status, _)) = received {
+            totals.record(&status);
+            window.record(&status);
+            if let (Some(out), WorkerStatus::Traced(query)) = (trace.as_mut(), &status) {
+                writeln!(out, "{}", query.line(&args)).unwrap();
+            }
+            if let WorkerStatus::ConnectFailed(error) = &status {
+                if connect_errors.insert(error.clone()) {
+                    eprintln!("CONNECT FAILED {}: {}", args.server, error);
+                }
+            }
+            if status == WorkerStatus::AllFinished {
+                all_finished += 1;
+                // The --reference thread may still be checking answers,
+                // which is no part of the benchmark
+                if all_finished == args.threads {
+                    ended = Some((Instant::now(), Cpu::now().since(&cpu)));
+                }
+            }
+        }
+        let finished = all_finished == args.threads + checkers;
+
+        if let Some(every) = every {
+            let now = Instant::now();
+            let active = window.sent + window.success + window.timeout + window.failed > 0;
+            if now - window_start >= every || (finished && active) {
+                let end = window_start
+                    .checked_add(every)
+                    .map_or(now, |end| now.min(end));
+                let (from, to) = (window_start - began, end - began);
+                progress(&report::interval_line(from, to, &window), to_stderr);
+                intervals.push(report::interval_json(from, to, &window));
+                window = Totals::new();
+                window_start = end;
+            }
+        }
+        if finished {
             break;
         }
     }
 
-    println!(
-        "ALLDONE sent: {}, success: {}, timeout: {}, failed: {}, thread finished: {}, percent: 100%, time: {}s",
-        sent,
-        success,
-        timeout,
-        failed,
-        all_finished,
-        now.elapsed().unwrap().as_secs_f32()
-    );
+    let (ended, cpu) = ended.unwrap();
+    Run {
+        // The totals and CPU time then still cover the whole run
+        elapsed: match warming_up {
+            true => ended - began,
+            false => ended.saturating_duration_since(measured),
+        },
+        cpu,
+        args,
+        totals,
+        intervals,
+    }
 }
 
-#[derive(Parser, Debug)]
+fn progress(line: &str, to_stderr: bool) {
+    if to_stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Answer queries locally with a configurable delay, loss, response
+    /// codes and faults, to test and calibrate dnsbench itself
+    Serve(ServeArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Number of threads
     #[clap(short = 'p', long, default_value = "10")]
     threads: u32,
@@ -107,88 +400,482 @@ struct Args {
     #[clap(short, long, default_value = "100")]
     number: u32,
 
+    /// Run for this long instead of sending --number queries per thread,
+    /// e.g. 60s, 5m or 1500ms
+    #[clap(long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+
+    /// Send queries for this long before the measured part of the run,
+    /// leaving them out of the final results. A run that ends before the
+    /// warmup does is reported in full
+    #[clap(long, value_parser = parse_duration)]
+    warmup: Option<Duration>,
+
     /// Domains file, from which the domains are randomly selected to send query
     #[clap(short, long, default_value = "domains.txt")]
     domains: String,
 
-    /// Query A or AAAA record
+    /// Query type, e.g. A, AAAA, MX, HTTPS or TYPE65534 (case-insensitive),
+    /// or a weighted mix such as A=60,AAAA=30,HTTPS=5,PTR=5
     #[clap(short, long, default_value = "A")]
-    record: String,
+    record: Mix,
+
+    /// Class of the queries, e.g. IN, CH or CLASS65280
+    #[clap(long, default_value = "IN", value_parser = encode::parse_class)]
+    class: u16,
+
+    /// How to write the letters of query names
+    #[clap(long, value_enum, default_value = "keep")]
+    case: Case,
 
-    /// DNS server address
-    #[clap(short, long)]
+    /// DNS server address; repeat it to benchmark several servers one
+    /// after another with the same queries and compare them
+    #[clap(short = 's', long = "server")]
+    servers: Vec<SocketAddr>,
+
+    /// File with more DNS server addresses, one per line
+    #[clap(long)]
+    server_file: Option<String>,
+
+    /// The server of the current run
+    #[clap(skip = SocketAddr::from(([0, 0, 0, 0], 0)))]
     server: SocketAddr,
 
+    /// Seed for picking domains, types and arrival times, so that runs can
+    /// be repeated with the same queries. Random if not set
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Local IP to send from; repeat it to spread the threads over several
+    /// source addresses
+    #[clap(long, alias = "bind")]
+    source: Vec<IpAddr>,
+
+    /// Local port or port range such as 20000-20999 to send from
+    #[clap(long, value_parser = parse_ports)]
+    source_port: Option<(u16, u16)>,
+
     /// Timeout for each request (ms)
     #[clap(short, long, default_value = "500")]
     timeout: u64,
 
-    /// Debug level, 0: no debug, 1: print debug info, 2: print all info
+    /// Transport used to reach the DNS server
+    #[clap(long, value_enum, default_value = "udp")]
+    transport: Transport,
+
+    /// TLS server name for SNI and certificate checks, defaults to the
+    /// server IP
+    #[clap(long)]
+    tls_name: Option<String>,
+
+    /// PEM file with the CA certificates to trust for TLS, instead of the
+    /// bundled web PKI roots. A self-signed server certificate can only be
+    /// trusted this way if it is not itself a CA, as one from a plain
+    /// `openssl req -x509` is: add `-addext basicConstraints=critical,CA:FALSE`
+    /// and `-addext subjectAltName=IP:<server ip>`, or use --insecure.
+    #[clap(long)]
+    ca_file: Option<String>,
+
+    /// Skip TLS certificate verification
+    #[clap(long)]
+    insecure: bool,
+
+    /// Disable TLS session resumption on reconnects
+    #[clap(long)]
+    no_resumption: bool,
+
+    /// HTTP method for DoH queries
+    #[clap(long, value_enum, default_value = "post")]
+    doh_method: DohMethod,
+
+    /// URL path of the DoH endpoint
+    #[clap(long, default_value = "/dns-query")]
+    doh_path: String,
+
+    /// Target total queries per second across all threads, sent on a fixed
+    /// timetable regardless of responses (open loop). Unlimited if not set
+    #[clap(short, long, value_parser = rate::parse_qps)]
+    qps: Option<f64>,
+
+    /// Arrival process used with --qps
+    #[clap(long, value_enum, default_value = "fixed")]
+    arrival: Arrival,
+
+    /// Max outstanding queries per thread; values above 1 pipeline queries
+    /// through separate sender and receiver threads on each socket or
+    /// connection
+    #[clap(short, long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    inflight: u16,
+
+    /// With --inflight, send and receive up to this many UDP datagrams per
+    /// system call, using sendmmsg and recvmmsg on Linux. A batch only takes
+    /// queries that are due already. Not for --io uring, which queues every
+    /// send and receive anyway
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..=64))]
+    batch: u16,
+
+    /// How workers drive their sockets. With uring, --inflight queries are
+    /// kept outstanding on each worker's socket or connection from a single
+    /// thread.
+    #[clap(long, value_enum, default_value = "threads")]
+    io: Io,
+
+    /// Check the answers against those of this server. Each distinct
+    /// question is asked there once, when its first answer comes in
+    #[clap(long, conflicts_with = "expected")]
+    reference: Option<SocketAddr>,
+
+    /// Check the answers against a file of JSON lines such as
+    /// {"qname": "example.com", "qtype": "A", "records": ["example.com 300 A 192.0.2.1"]}
+    #[clap(long)]
+    expected: Option<String>,
+
+    /// Write every finished query as a JSON line to this file
+    #[clap(long)]
+    trace_file: Option<String>,
+
+    /// Replay the queries of a --trace-file with their recorded timing,
+    /// names and types, instead of random queries from --domains. Recorded
+    /// threads are spread over the --threads workers. A trace of several
+    /// servers needs --replay-server
+    #[clap(long)]
+    replay: Option<String>,
+
+    /// Replay only the queries that were sent to this server
+    #[clap(long, requires = "replay")]
+    replay_server: Option<SocketAddr>,
+
+    /// Retry truncated UDP answers over TCP
+    #[clap(long)]
+    tcp_fallback: bool,
+
+    /// Add an EDNS(0) OPT record to the queries. Implied by any of the
+    /// EDNS options below
+    #[clap(long)]
+    edns: bool,
+
+    /// EDNS UDP payload size to advertise [default: 1232]
+    #[clap(long)]
+    bufsize: Option<u16>,
+
+    /// Set the DNSSEC OK bit
+    #[clap(long)]
+    dnssec: bool,
+
+    /// EDNS Client Subnet to send, e.g. 192.0.2.0/24 or 2001:db8::/56
+    #[clap(long, value_parser = edns::parse_subnet)]
+    ecs: Option<(IpAddr, u8)>,
+
+    /// Send a random client cookie
+    #[clap(long)]
+    cookie: bool,
+
+    /// Ask for the server's NSID
+    #[clap(long)]
+    nsid: bool,
+
+    /// Pad queries to a multiple of this many bytes, e.g. 128
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    padding: Option<u16>,
+
+    /// Use random transaction IDs like a stub resolver would, instead of
+    /// counting up on each socket
+    #[clap(long)]
+    random_ids: bool,
+
+    /// Format of the final results
+    #[clap(short, long, value_enum, default_value = "text")]
+    output: Output,
+
+    /// Write the json or csv results to this file and keep the text summary
+    /// on stdout
+    #[clap(long)]
+    output_file: Option<String>,
+
+    /// Print the rate, success rate and latency of each interval of this
+    /// many seconds while the run is going
+    #[clap(long, value_parser = parse_interval)]
+    interval: Option<f64>,
+
+    /// Debug level, 0: no debug, 1: interval statistics every second,
+    /// 2: also print every query and answer
     #[clap(short = 'v', long, default_value = "0")]
     debug: u32,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 enum WorkerStatus {
-    Sent,
-    Success,
-    Timeout,
-    Failed,
+    Sent(QType),
+    /// Any response to the query, whatever its RCODE. Round-trip time is
+    /// measured from just before `send_req`, or from the scheduled send time
+    /// in --qps mode
+    Success {
+        qtype: QType,
+        rtt: Duration,
+        rcode: Rcode,
+        answers: u16,
+        /// The UDP answer was truncated and this one came from retrying
+        /// over TCP
+        over_tcp: bool,
+        /// The parts of our OPT record the server sent back, with EDNS
+        echo: Option<Echo>,
+        /// How the answer compares with the right one, with --reference or
+        /// --expected, unless the right one has yet to be looked up
+        check: Option<Check>,
+    },
+    Timeout(QType),
+    Failed(QType),
+    /// A new connection was set up before sending a query
+    Connected(Setup),
+    /// The query was never sent because the connection could not be set up,
+    /// for this reason
+    ConnectFailed(String),
+    /// DoH answered with this non-200 HTTP status instead of a DNS message
+    HttpStatus(QType, u16),
+    /// The answer had the TC bit set; the query's outcome follows, from a
+    /// TCP retry with --tcp-fallback
+    Truncated(QType),
+    /// A response that belongs to no outstanding query: late, for another
+    /// ID or question, from another address, or malformed
+    Stray,
+    /// An answer checked against the --reference server's after the fact
+    Checked(Check),
+    /// The outcome of a query together with the query, for --trace-file
+    Traced(Box<Trace>),
     AllFinished,
 }
 
-fn send_req(
-    socket: &UdpSocket,
+/// Sets up the connection before a query if needed, reporting the cost of
+/// new connections. Returns false if no connection could be made.
+fn connect(conn: &mut dyn Conn, tx: &Sender<(WorkerStatus, ThreadId)>) -> bool {
+    match conn.connect() {
+        Ok(setup) => {
+            if let Some(setup) = setup {
+                tx.send((WorkerStatus::Connected(setup), thread::current().id()))
+                    .unwrap();
+            }
+            true
+        }
+        Err(e) => {
+            tx.send((
+                WorkerStatus::ConnectFailed(e.to_string()),
+                thread::current().id(),
+            ))
+            .unwrap();
+            false
+        }
+    }
+}
+
+/// A sent query waiting for its response
+struct Query<'a> {
     id: u16,
-    domain: &str,
-    query_type: QueryType,
-) -> (WorkerStatus, ThreadId) {
-    let mut builder = dns_parser::Builder::new_query(id, true);
-    builder.add_question(domain, true, query_type, QueryClass::IN);
-    let mut packet = builder.build().unwrap();
-    let len = packet.len();
-    packet[len - 2] = 0; // fix dns_parser bug (unclear why)
+    qname: &'a str,
+    qtype: QType,
+    /// Its number among the pre-encoded packets, if it is one of them
+    index: Option<usize>,
+    /// Where its latency is measured from
+    start: Instant,
+}
 
+/// Sends `query`, encoding it into `packet`, which is reused between sends
+fn send_req(
+    conn: &mut dyn Conn,
+    query: &Query,
+    encoder: &Encoder,
+    packet: &mut Vec<u8>,
+    rng: &mut impl Rng,
+) -> (WorkerStatus, ThreadId) {
+    encoder.write(packet, query.id, query.qname, query.qtype, query.index, rng);
     (
-        match socket.send(&packet) {
-            Ok(_) => WorkerStatus::Sent,
-            Err(_) => WorkerStatus::Failed,
+        match conn.send(packet) {
+            Ok(_) => WorkerStatus::Sent(query.qtype),
+            Err(_) => WorkerStatus::Failed(query.qtype),
         },
         thread::current().id(),
     )
 }
 
-fn recv_resp(socket: &UdpSocket, id: u16, timeout: u64, debug: u32) -> (WorkerStatus, ThreadId) {
-    let mut packet = [0; 4096];
-    socket
-        .set_read_timeout(Some(std::time::Duration::from_millis(timeout)))
-        .unwrap();
-    match socket.recv(&mut packet) {
-        Ok(_) => (),
-        Err(e) => {
-            return (
-                match e.kind() {
-                    std::io::ErrorKind::TimedOut => WorkerStatus::Timeout,
-                    _ => WorkerStatus::Failed,
-                },
-                thread::current().id(),
-            );
+fn recv_resp(
+    conn: &mut dyn Conn,
+    query: &Query,
+    deadline: Instant,
+    handler: &Handler,
+    packet: &mut [u8],
+) -> WorkerStatus {
+    loop {
+        let len = match conn.recv(packet, deadline) {
+            Ok(len) => len,
+            Err(e) => {
+                match doh::failure(&e) {
+                    // Left over from an earlier query that already timed out
+                    Some(failure) if failure.id != query.id => handler.stray(),
+                    Some(HttpFailure {
+                        status: Some(status),
+                        ..
+                    }) => return WorkerStatus::HttpStatus(query.qtype, *status),
+                    _ if transport::is_foreign(&e) => handler.stray(),
+                    _ if transport::is_timeout(&e) => return WorkerStatus::Timeout(query.qtype),
+                    _ => return WorkerStatus::Failed(query.qtype),
+                }
+                continue;
+            }
+        };
+
+        match message::parse(&packet[..len]) {
+            Some(v) if v.matches(query.id, query.qname, query.qtype, handler.encoder.qclass) => {
+                if v.truncated {
+                    handler.truncated(query.qtype);
+                    if handler.fallback.is_some() {
+                        return handler.retry(query);
+                    }
+                }
+                return handler.answer(query, &v, false);
+            }
+            _ => handler.stray(),
         }
-    };
+    }
+}
+
+/// What a worker does with the answers it gets
+#[derive(Clone)]
+struct Handler {
+    /// Index of the worker, for its --trace-file lines
+    worker: u32,
+    tracing: bool,
+    debug: u32,
+    /// Whether -v 2 lines go to stderr, as stdout is left to the report
+    to_stderr: bool,
+    timeout: Duration,
+    encoder: Encoder,
+    validator: Option<Arc<Validator>>,
+    /// Server and local address to retry truncated UDP answers over TCP
+    /// from, with --tcp-fallback
+    fallback: Option<(SocketAddr, Local)>,
+    tx: Sender<(WorkerStatus, ThreadId)>,
+}
+
+impl Handler {
+    /// The outcome of a query that got the answer `v`
+    fn answer(&self, query: &Query, v: &Message, over_tcp: bool) -> WorkerStatus {
+        let rtt = query.start.elapsed();
+        if self.debug >= 2 {
+            progress(&format!("OK, {}", v), self.to_stderr);
+        }
+        WorkerStatus::Success {
+            qtype: query.qtype,
+            rtt,
+            rcode: Rcode::of(v),
+            answers: v.answers.len() as u16,
+            over_tcp,
+            echo: self.encoder.edns.as_ref().map(|edns| edns.echo(v)),
+            check: self
+                .validator
+                .as_ref()
+                .and_then(|validator| validator.check(query.qname, query.qtype, v)),
+        }
+    }
 
-    match dns_parser::Packet::parse(&packet) {
-        Ok(v) => {
-            if v.header.id == id {
-                if debug >= 2 {
-                    println!("OK, {} -> {:?}", v.questions[0].qname, v.answers);
+    /// Asks a truncated query again on a new TCP connection, as a stub
+    /// resolver would. The retry gets a timeout of its own, and its latency
+    /// still counts from the start of the query.
+    fn retry(&self, query: &Query) -> WorkerStatus {
+        let (server, local) = self.fallback.clone().unwrap();
+        let mut conn = transport::tcp(server, local, self.timeout);
+        let packet =
+            self.encoder
+                .encode(query.id, query.qname, query.qtype, &mut rand::thread_rng());
+        if conn.connect().and_then(|_| conn.send(&packet)).is_err() {
+            return WorkerStatus::Failed(query.qtype);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0; 65535];
+        loop {
+            match conn.recv(&mut buf, deadline) {
+                Ok(len) => {
+                    if let Some(v) = message::parse(&buf[..len]) {
+                        if v.matches(query.id, query.qname, query.qtype, self.encoder.qclass) {
+                            return self.answer(query, &v, true);
+                        }
+                    }
                 }
-                (WorkerStatus::Success, thread::current().id())
-            } else {
-                recv_resp(socket, id, timeout, debug)
+                Err(e) if transport::is_timeout(&e) => return WorkerStatus::Timeout(query.qtype),
+                Err(_) => return WorkerStatus::Failed(query.qtype),
             }
         }
-        Err(_) => (WorkerStatus::Failed, thread::current().id()),
     }
+
+    fn truncated(&self, qtype: QType) {
+        self.tx
+            .send((WorkerStatus::Truncated(qtype), thread::current().id()))
+            .unwrap();
+    }
+
+    fn stray(&self) {
+        self.tx
+            .send((WorkerStatus::Stray, thread::current().id()))
+            .unwrap();
+    }
+}
+
+/// Parses durations such as `90`, `90s`, `1500ms`, `5m` or `1h`; a plain
+/// number is in seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: f64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", s))?;
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("unknown unit in duration `{}`", s)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid duration `{}`", s))
+}
+
+/// Parses an --interval, a positive number of seconds
+fn parse_interval(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("invalid interval `{}`", s))?;
+    match secs > 0.0 && Duration::try_from_secs_f64(secs).is_ok() {
+        true => Ok(secs),
+        false => Err(format!(
+            "interval `{}` must be a positive number of seconds",
+            s
+        )),
+    }
+}
+
+/// Parses a port such as `5300` or an inclusive range such as `5300-5399`
+fn parse_ports(s: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid port range `{}`", s);
+    let (low, high) = s.split_once('-').unwrap_or((s, s));
+    let low: u16 = low.trim().parse().map_err(|_| invalid())?;
+    let high: u16 = high.trim().parse().map_err(|_| invalid())?;
+    if low == 0 || low > high {
+        return Err(invalid());
+    }
+    Ok((low, high))
+}
+
+/// Reads a --server-file: one `address:port` per line, with blank lines and
+/// `#` comments skipped
+fn read_servers(file: &str) -> Result<Vec<SocketAddr>, String> {
+    fs::read_to_string(file)
+        .map_err(|e| format!("cannot read {}: {}", file, e))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .map_err(|_| format!("invalid server `{}` in {}", line, file))
+        })
+        .collect()
 }
 
 fn read_domains(file: &str) -> Vec<String> {
@@ -204,7 +891,7 @@ fn read_domains(file: &str) -> Vec<String> {
                     continue;
                 }
                 let domain = line.to_string();
-                if let Ok(_) = parse_domain_name(&domain) {
+                if parse_domain_name(&domain).is_ok() {
                     domains.push(domain);
                 }
             }
@@ -213,3 +900,33 @@ fn read_domains(file: &str) -> Vec<String> {
     }
     domains
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        let ok = |s| parse_duration(s).unwrap();
+        assert_eq!(ok("90"), Duration::from_secs(90));
+        assert_eq!(ok("1.5s"), Duration::from_millis(1500));
+        assert_eq!(ok("1500ms"), Duration::from_millis(1500));
+        assert_eq!(ok("5 ms"), Duration::from_millis(5));
+        assert_eq!(ok("5m"), Duration::from_secs(300));
+        assert_eq!(ok("1h"), Duration::from_secs(3600));
+        assert_eq!(ok("0"), Duration::ZERO);
+        for bad in ["", "s", "ten", "5d", "-1s", "1e30h", "NaN"] {
+            assert!(parse_duration(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_ports("5353"), Ok((5353, 5353)));
+        assert_eq!(parse_ports("10000-10999"), Ok((10000, 10999)));
+        assert_eq!(parse_ports(" 1 - 65535 "), Ok((1, 65535)));
+        for bad in ["", "0", "0-10", "20-10", "1-65536", "a-b", "1-2-3", "-5"] {
+            assert!(parse_ports(bad).is_err(), "{}", bad);
+        }
+    }
+}
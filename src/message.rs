@@ -0,0 +1,607 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// A query or record type, including ones without a mnemonic (`TYPE65534`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QType(pub u16);
+
+const TYPES: &[(&str, u16)] = &[
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("HINFO", 13),
+    ("MX", 15),
+    ("TXT", 16),
+    ("RP", 17),
+    ("AFSDB", 18),
+    ("SIG", 24),
+    ("KEY", 25),
+    ("AAAA", 28),
+    ("LOC", 29),
+    ("SRV", 33),
+    ("NAPTR", 35),
+    ("KX", 36),
+    ("CERT", 37),
+    ("DNAME", 39),
+    ("OPT", 41),
+    ("APL", 42),
+    ("DS", 43),
+    ("SSHFP", 44),
+    ("IPSECKEY", 45),
+    ("RRSIG", 46),
+    ("NSEC", 47),
+    ("DNSKEY", 48),
+    ("DHCID", 49),
+    ("NSEC3", 50),
+    ("NSEC3PARAM", 51),
+    ("TLSA", 52),
+    ("SMIMEA", 53),
+    ("HIP", 55),
+    ("CDS", 59),
+    ("CDNSKEY", 60),
+    ("OPENPGPKEY", 61),
+    ("CSYNC", 62),
+    ("ZONEMD", 63),
+    ("SVCB", 64),
+    ("HTTPS", 65),
+    ("SPF", 99),
+    ("EUI48", 108),
+    ("EUI64", 109),
+    ("TKEY", 249),
+    ("TSIG", 250),
+    ("IXFR", 251),
+    ("AXFR", 252),
+    ("ANY", 255),
+    ("URI", 256),
+    ("CAA", 257),
+];
+
+impl FromStr for QType {
+    type Err = String;
+
+    /// Accepts mnemonics in any case as well as the RFC 3597 `TYPEnnn` form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(&(_, code)) = TYPES.iter().find(|(name, _)| *name == upper) {
+            return Ok(QType(code));
+        }
+        upper
+            .strip_prefix("TYPE")
+            .and_then(|code| code.parse().ok())
+            .map(QType)
+            .ok_or_else(|| format!("unknown query type `{}`", s))
+    }
+}
+
+impl fmt::Display for QType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match TYPES.iter().find(|(_, code)| *code == self.0) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "TYPE{}", self.0),
+        }
+    }
+}
+
+/// Response code of an answered query. NOERROR is split by whether the
+/// answer section is empty, which is a NODATA response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rcode {
+    NoError,
+    NoData,
+    NxDomain,
+    ServFail,
+    Refused,
+    FormErr,
+    NotImp,
+    Other,
+}
+
+impl Rcode {
+    pub const ALL: [Rcode; 8] = [
+        Rcode::NoError,
+        Rcode::NoData,
+        Rcode::NxDomain,
+        Rcode::ServFail,
+        Rcode::Refused,
+        Rcode::FormErr,
+        Rcode::NotImp,
+        Rcode::Other,
+    ];
+
+    /// The RCODE header value; NODATA is a NOERROR without answers
+    pub fn code(self) -> u8 {
+        match self {
+            Rcode::NoError | Rcode::NoData => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NxDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            // Not a code of its own; rejected by `from_str`
+            Rcode::Other => 15,
+        }
+    }
+
+    pub fn of(msg: &Message) -> Rcode {
+        match msg.rcode {
+            0 if msg.answers.is_empty() => Rcode::NoData,
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NxDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            _ => Rcode::Other,
+        }
+    }
+}
+
+impl fmt::Display for Rcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rcode::NoError => "NOERROR",
+            Rcode::NoData => "NODATA",
+            Rcode::NxDomain => "NXDOMAIN",
+            Rcode::ServFail => "SERVFAIL",
+            Rcode::Refused => "REFUSED",
+            Rcode::FormErr => "FORMERR",
+            Rcode::NotImp => "NOTIMP",
+            Rcode::Other => "other",
+        })
+    }
+}
+
+impl FromStr for Rcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Rcode::ALL
+            .into_iter()
+            .filter(|rcode| *rcode != Rcode::Other)
+            .find(|rcode| rcode.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown response code `{}`", s))
+    }
+}
+
+/// A parsed DNS message. Any type or class is accepted and record data is
+/// kept as raw bytes.
+pub struct Message<'a> {
+    pub id: u16,
+    /// The QR flag
+    pub response: bool,
+    /// The TC flag
+    pub truncated: bool,
+    /// Low four bits of the header flags; extended RCODEs are not merged in
+    pub rcode: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record<'a>>,
+    /// The OPT record of the additional section, if there is one
+    pub opt: Option<Opt>,
+}
+
+/// EDNS(0) pseudo-record
+pub struct Opt {
+    /// The DNSSEC OK bit
+    pub dnssec_ok: bool,
+    /// Option codes in the order they appear
+    pub options: Vec<u16>,
+}
+
+pub struct Question {
+    pub name: String,
+    pub qtype: QType,
+    pub qclass: u16,
+}
+
+impl Message<'_> {
+    /// Whether this is the response to our query with this ID, name, type
+    /// and class. Error responses may leave out the question.
+    pub fn matches(&self, id: u16, qname: &str, qtype: QType, qclass: u16) -> bool {
+        if !self.response || self.id != id {
+            return false;
+        }
+        match self.questions.as_slice() {
+            [] => self.rcode != 0,
+            [question] => {
+                question.qtype == qtype
+                    && question.qclass == qclass
+                    && question
+                        .name
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(qname.trim_end_matches('.'))
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Record<'a> {
+    pub name: String,
+    pub rtype: QType,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+    /// The whole message, for names compressed inside `rdata`
+    msg: &'a [u8],
+    rdata_pos: usize,
+}
+
+/// Parses a DNS message, or returns None if it is malformed
+pub fn parse(msg: &[u8]) -> Option<Message<'_>> {
+    let word = |pos: usize| Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?));
+
+    let id = word(0)?;
+    let flags = word(2)?;
+    let rcode = (flags & 0x0f) as u8;
+    let (qdcount, ancount, nscount, arcount) = (word(4)?, word(6)?, word(8)?, word(10)?);
+    let mut pos = 12;
+
+    let mut questions = Vec::new();
+    for _ in 0..qdcount {
+        let (name, next) = read_name(msg, pos)?;
+        questions.push(Question {
+            name,
+            qtype: QType(word(next)?),
+            qclass: word(next + 2)?,
+        });
+        pos = next + 4;
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        let (name, next) = read_name(msg, pos)?;
+        let ttl = u32::from_be_bytes(msg.get(next + 4..next + 8)?.try_into().ok()?);
+        let len = word(next + 8)? as usize;
+        let rdata_pos = next + 10;
+        answers.push(Record {
+            name,
+            rtype: QType(word(next)?),
+            ttl,
+            rdata: msg.get(rdata_pos..rdata_pos + len)?,
+            msg,
+            rdata_pos,
+        });
+        pos = rdata_pos + len;
+    }
+
+    Some(Message {
+        id,
+        response: flags & 0x8000 != 0,
+        truncated: flags & 0x0200 != 0,
+        rcode,
+        questions,
+        answers,
+        // A broken authority or additional section does not spoil the rest
+        opt: find_opt(msg, pos, nscount as usize + arcount as usize),
+    })
+}
+
+/// Looks for an OPT record among the `count` records from `pos` on
+fn find_opt(msg: &[u8], mut pos: usize, count: usize) -> Option<Opt> {
+    let word = |pos: usize| Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?));
+    for _ in 0..count {
+        let (_, next) = read_name(msg, pos)?;
+        let len = word(next + 8)? as usize;
+        let rdata = next + 10;
+        if word(next)? == 41 {
+            let mut options = Vec::new();
+            let mut at = rdata;
+            while at + 4 <= rdata + len {
+                options.push(word(at)?);
+                at += 4 + word(at + 2)? as usize;
+            }
+            return Some(Opt {
+                dnssec_ok: word(next + 6)? & 0x8000 != 0,
+                options,
+            });
+        }
+        pos = rdata + len;
+    }
+    None
+}
+
+/// Reads a possibly compressed name starting at `pos`. Returns it in
+/// presentation form without the trailing dot, together with the position
+/// just after it.
+pub fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                pos += 1;
+                break;
+            }
+            0x00 => {
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                for &b in msg.get(pos + 1..pos + 1 + len)? {
+                    if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'*' {
+                        name.push(b as char);
+                    } else {
+                        name.push_str(&format!("\\{:03}", b));
+                    }
+                }
+                pos += 1 + len;
+            }
+            0xc0 => {
+                end.get_or_insert(pos + 2);
+                jumps += 1;
+                if jumps > 127 {
+                    return None;
+                }
+                pos = (len & 0x3f) << 8 | *msg.get(pos + 1)? as usize;
+            }
+            _ => return None,
+        }
+    }
+    if name.is_empty() {
+        name.push('.');
+    }
+    Some((name, end.unwrap_or(pos)))
+}
+
+impl Record<'_> {
+    /// The record data in presentation form
+    pub fn data(&self) -> String {
+        RecordData(self).to_string()
+    }
+}
+
+struct RecordData<'a, 'b>(&'b Record<'a>);
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.name,
+            self.ttl,
+            self.rtype,
+            RecordData(self)
+        )
+    }
+}
+
+impl fmt::Display for RecordData<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let record = self.0;
+        match (record.rtype.0, record.rdata.len(), fields(record)) {
+            (1, 4, _) => write!(
+                f,
+                "{}",
+                Ipv4Addr::from(<[u8; 4]>::try_from(record.rdata).unwrap())
+            ),
+            (28, 16, _) => write!(
+                f,
+                "{}",
+                Ipv6Addr::from(<[u8; 16]>::try_from(record.rdata).unwrap())
+            ),
+            (_, _, Some(fields)) => f.write_str(&fields.join(" ")),
+            // RFC 3597 generic form
+            _ => {
+                write!(f, "\\# {}", record.rdata.len())?;
+                if !record.rdata.is_empty() {
+                    f.write_str(" ")?;
+                }
+                record.rdata.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+/// A field of record data
+enum Field {
+    Name,
+    U16,
+    U32,
+}
+
+/// The fields of the record types whose data holds names, which may be
+/// compressed
+fn layout(rtype: QType) -> &'static [Field] {
+    use Field::*;
+    match rtype.0 {
+        // NS, MD, MF, CNAME, MB, MG, MR, PTR and DNAME
+        2..=5 | 7..=9 | 12 | 39 => &[Name],
+        // SOA
+        6 => &[Name, Name, U32, U32, U32, U32, U32],
+        // MINFO and RP
+        14 | 17 => &[Name, Name],
+        // MX, AFSDB, RT and KX
+        15 | 18 | 21 | 36 => &[U16, Name],
+        // SRV
+        33 => &[U16, U16, U16, Name],
+        _ => &[],
+    }
+}
+
+/// The data of a record with names in it, field by field with the names
+/// decompressed, or None for other types or if it does not fit the layout
+fn fields(record: &Record) -> Option<Vec<String>> {
+    let layout = layout(record.rtype);
+    if layout.is_empty() {
+        return None;
+    }
+    let msg = record.msg;
+    let end = record.rdata_pos + record.rdata.len();
+    let mut pos = record.rdata_pos;
+    let mut fields = Vec::new();
+    for field in layout {
+        let (value, next) = match field {
+            Field::Name => read_name(msg, pos)?,
+            Field::U16 => {
+                let value = u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?);
+                (value.to_string(), pos + 2)
+            }
+            Field::U32 => {
+                let value = u32::from_be_bytes(msg.get(pos..pos + 4)?.try_into().ok()?);
+                (value.to_string(), pos + 4)
+            }
+        };
+        if next > end {
+            return None;
+        }
+        fields.push(value);
+        pos = next;
+    }
+    (pos == end).then_some(fields)
+}
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(question) = self.questions.first() {
+            write!(f, "{} {}", question.name, question.qtype)?;
+        }
+        f.write_str(" -> [")?;
+        for (i, answer) in self.answers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", answer)?;
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response for `example.com` with one answer per rdata, each of
+    /// type `rtype` and owned by the question's name through a pointer
+    fn response(rtype: u16, rdatas: &[&[u8]]) -> Vec<u8> {
+        let mut msg = vec![0, 1, 0x81, 0x80, 0, 1, 0, rdatas.len() as u8, 0, 0, 0, 0];
+        msg.extend(b"\x07example\x03com\x00");
+        msg.extend(rtype.to_be_bytes());
+        msg.extend([0, 1]);
+        for rdata in rdatas {
+            msg.extend([0xc0, 12]);
+            msg.extend(rtype.to_be_bytes());
+            msg.extend([0, 1, 0, 0, 1, 44]);
+            msg.extend((rdata.len() as u16).to_be_bytes());
+            msg.extend(*rdata);
+        }
+        msg
+    }
+
+    #[test]
+    fn header_and_question() {
+        let mut msg = response(1, &[b"\xc0\x00\x02\x01"]);
+        msg[2] |= 0x02;
+        msg[3] |= 0x03;
+        let msg = parse(&msg).unwrap();
+        assert_eq!(msg.id, 1);
+        assert!(msg.response && msg.truncated);
+        assert_eq!(msg.rcode, 3);
+        let question = &msg.questions[0];
+        assert_eq!(
+            (question.name.as_str(), question.qtype, question.qclass),
+            ("example.com", QType(1), 1)
+        );
+        let answer = &msg.answers[0];
+        assert_eq!((answer.name.as_str(), answer.ttl), ("example.com", 300));
+        assert_eq!(answer.data(), "192.0.2.1");
+        assert!(msg.opt.is_none());
+    }
+
+    #[test]
+    fn matches() {
+        let msg = response(1, &[]);
+        let msg = parse(&msg).unwrap();
+        assert!(msg.matches(1, "EXAMPLE.com.", QType(1), 1));
+        assert!(!msg.matches(2, "example.com", QType(1), 1));
+        assert!(!msg.matches(1, "example.org", QType(1), 1));
+        assert!(!msg.matches(1, "example.com", QType(28), 1));
+        assert!(!msg.matches(1, "example.com", QType(1), 3));
+    }
+
+    #[test]
+    fn malformed() {
+        let msg = response(1, &[b"\xc0\x00\x02\x01"]);
+        // Cut short anywhere
+        for len in 0..msg.len() {
+            assert!(parse(&msg[..len]).is_none(), "{}", len);
+        }
+        // A record longer than the message
+        let mut long = msg.clone();
+        let at = long.len() - 5;
+        long[at] = 5;
+        assert!(parse(&long).is_none());
+    }
+
+    #[test]
+    fn read_names() {
+        let msg = b"\x03www\x07Example\x00\xc0\x04\x00\xc0\x0e\x01*\x02a.\x00";
+        assert_eq!(read_name(msg, 0), Some(("www.Example".to_string(), 13)));
+        // A pointer ends the name in place
+        assert_eq!(read_name(msg, 13), Some(("Example".to_string(), 15)));
+        assert_eq!(read_name(msg, 12), Some((".".to_string(), 13)));
+        // Characters outside hostnames are escaped
+        assert_eq!(read_name(msg, 18), Some(("*.a\\046".to_string(), 24)));
+        // A label running past the end, a pointer loop, reserved label
+        // types and names cut short
+        assert_eq!(read_name(msg, 16), None);
+        assert_eq!(read_name(b"\x01a\xc0\x00", 0), None);
+        assert_eq!(read_name(b"\x40", 0), None);
+        assert_eq!(read_name(b"\x03ww", 0), None);
+        assert_eq!(read_name(b"\xc0", 0), None);
+    }
+
+    #[test]
+    fn opt() {
+        let mut msg = response(1, &[]);
+        msg[11] = 2;
+        // An authority-style record first, then OPT with the DO bit, an
+        // ECS and a cookie option
+        msg.extend(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x01\x00\x04\xc0\x00\x02\x01");
+        msg.extend(b"\x00\x00\x29\x04\xd0\x00\x00\x80\x00\x00\x0e");
+        msg.extend(b"\x00\x08\x00\x02\x00\x01\x00\x0a\x00\x04\x01\x02\x03\x04");
+        let opt = parse(&msg).unwrap().opt.unwrap();
+        assert!(opt.dnssec_ok);
+        assert_eq!(opt.options, [8, 10]);
+
+        // A broken additional section only loses the OPT record
+        let cut = &msg[..msg.len() - 20];
+        let parsed = parse(cut).unwrap();
+        assert!(parsed.opt.is_none());
+        assert_eq!(parsed.questions.len(), 1);
+    }
+
+    #[test]
+    fn names_in_data() {
+        let msg = response(
+            15,
+            &[
+                b"\x00\x0a\x04mail\xc0\x0c",
+                b"\x00\x0a\x04MAIL\x07EXAMPLE\x03com\x00",
+            ],
+        );
+        let msg = parse(&msg).unwrap();
+        assert_eq!(msg.answers[0].data(), "10 mail.example.com");
+        assert_eq!(msg.answers[1].data(), "10 MAIL.EXAMPLE.com");
+
+        let soa = b"\xc0\x0c\x05admin\xc0\x0c\x00\x00\x00\x01\x00\x00\x0e\x10\x00\x00\x01\x2c\x00\x09\x3a\x80\x00\x00\x00\x3c";
+        let msg = response(6, &[soa]);
+        assert_eq!(
+            parse(&msg).unwrap().answers[0].data(),
+            "example.com admin.example.com 1 3600 300 604800 60"
+        );
+    }
+
+    #[test]
+    fn data_not_fitting_its_type() {
+        // An MX without its name, and one with bytes after it
+        let msg = response(15, &[b"\x00\x0a", b"\x00\x0a\xc0\x0c\xff"]);
+        let msg = parse(&msg).unwrap();
+        assert_eq!(msg.answers[0].data(), "\\# 2 000a");
+        assert_eq!(msg.answers[1].data(), "\\# 5 000ac00cff");
+    }
+}
@@ -0,0 +1,116 @@
+use std::{fmt, str::FromStr};
+
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+
+use crate::message::QType;
+
+/// Query types to send and their relative weights, e.g. `A=60,AAAA=30,HTTPS=10`.
+/// `serve` uses the same form for the response codes it answers with.
+#[derive(Clone, Debug)]
+pub struct Mix<T = QType> {
+    pub types: Vec<T>,
+    pub weights: Vec<u32>,
+    index: WeightedIndex<u32>,
+}
+
+impl<T: Copy> Mix<T> {
+    /// Picks the type of the next query
+    pub fn sample(&self, rng: &mut impl Rng) -> T {
+        self.types[self.pick(rng)]
+    }
+
+    /// Like `sample`, but returns the type's place in `types`
+    pub fn pick(&self, rng: &mut impl Rng) -> usize {
+        self.index.sample(rng)
+    }
+}
+
+impl<T: FromStr<Err = String> + PartialEq + fmt::Display> FromStr for Mix<T> {
+    type Err = String;
+
+    /// A weight may be left out, so a plain `AAAA` means AAAA queries only
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut types = Vec::new();
+        let mut weights = Vec::new();
+        for entry in s.split(',') {
+            let (qtype, weight) = match entry.split_once('=') {
+                Some((qtype, weight)) => (
+                    qtype,
+                    weight
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|_| format!("invalid weight in `{}`", entry))?,
+                ),
+                None => (entry, 1),
+            };
+            let qtype: T = qtype.trim().parse()?;
+            if types.contains(&qtype) {
+                return Err(format!("{} given twice", qtype));
+            }
+            types.push(qtype);
+            weights.push(weight);
+        }
+        let index =
+            WeightedIndex::new(&weights).map_err(|_| "weights must not all be zero".to_string())?;
+        Ok(Mix {
+            types,
+            weights,
+            index,
+        })
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Mix<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (qtype, weight)) in self.types.iter().zip(&self.weights).enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", qtype, weight)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Rcode;
+
+    #[test]
+    fn parse() {
+        let mix: Mix = "A=60, aaaa=30,TYPE65=10".parse().unwrap();
+        assert_eq!(mix.types, [QType(1), QType(28), QType(65)]);
+        assert_eq!(mix.weights, [60, 30, 10]);
+        assert_eq!(mix.to_string(), "A=60,AAAA=30,HTTPS=10");
+
+        let mix: Mix = "AAAA".parse().unwrap();
+        assert_eq!((mix.types, mix.weights), (vec![QType(28)], vec![1]));
+
+        let mix: Mix<Rcode> = "NOERROR=9,NXDOMAIN=1".parse().unwrap();
+        assert_eq!(mix.types, [Rcode::NoError, Rcode::NxDomain]);
+    }
+
+    #[test]
+    fn invalid() {
+        for bad in [
+            "",
+            "A=",
+            "A=-1",
+            "A=x",
+            "BOGUS",
+            "A,a",
+            "A=0,AAAA=0",
+            "A=1,",
+        ] {
+            assert!(bad.parse::<Mix>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn zero_weight_never_sampled() {
+        let mix: Mix = "A=1,AAAA=0".parse().unwrap();
+        let mut rng = rand::thread_rng();
+        assert!((0..1000).all(|_| mix.sample(&mut rng) == QType(1)));
+    }
+}
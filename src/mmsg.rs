@@ -0,0 +1,98 @@
+use std::{
+    ffi::c_void,
+    io, mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    ptr,
+};
+
+use socket2::{SockAddr, SockAddrStorage};
+
+/// Most datagrams sent or received by one system call, the limit of --batch
+pub const MAX_BATCH: usize = 64;
+
+/// Sends `msgs` to `addr` with sendmmsg, as many calls as it takes.
+/// Returns how many were sent before the first failure.
+pub fn send(socket: &UdpSocket, addr: SocketAddr, msgs: &[Vec<u8>]) -> io::Result<usize> {
+    let addr = SockAddr::from(addr);
+    let count = msgs.len().min(MAX_BATCH);
+    // SAFETY: all zeros is a valid iovec and mmsghdr
+    let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+    for (i, msg) in msgs.iter().take(count).enumerate() {
+        iovecs[i].iov_base = msg.as_ptr() as *mut c_void;
+        iovecs[i].iov_len = msg.len();
+        let header = &mut headers[i].msg_hdr;
+        header.msg_name = addr.as_ptr() as *mut c_void;
+        header.msg_namelen = addr.len();
+        header.msg_iov = &mut iovecs[i];
+        header.msg_iovlen = 1;
+    }
+
+    let mut sent = 0;
+    while sent < count {
+        // SAFETY: the headers point into `iovecs`, `msgs` and `addr`, which
+        // all outlive the call
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers[sent..].as_mut_ptr(),
+                (count - sent) as _,
+                0,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return if sent == 0 { Err(e) } else { Ok(sent) };
+        }
+        sent += n as usize;
+    }
+    Ok(sent)
+}
+
+/// Receives up to one datagram per buffer with recvmmsg, waiting for the
+/// first one only, up to the socket's read timeout. Each one's length and
+/// source go to `received`; returns how many there are.
+pub fn recv(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    received: &mut [(usize, Option<SocketAddr>)],
+) -> io::Result<usize> {
+    let count = bufs.len().min(received.len()).min(MAX_BATCH);
+    let mut names: [SockAddrStorage; MAX_BATCH] =
+        std::array::from_fn(|_| SockAddrStorage::zeroed());
+    // SAFETY: all zeros is a valid iovec and mmsghdr
+    let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+    for (i, buf) in bufs.iter_mut().take(count).enumerate() {
+        iovecs[i].iov_base = buf.as_mut_ptr() as *mut c_void;
+        iovecs[i].iov_len = buf.len();
+        let header = &mut headers[i].msg_hdr;
+        header.msg_name = &mut names[i] as *mut SockAddrStorage as *mut c_void;
+        header.msg_namelen = names[i].size_of();
+        header.msg_iov = &mut iovecs[i];
+        header.msg_iovlen = 1;
+    }
+
+    // SAFETY: the headers point into `iovecs`, `bufs` and `names`, which all
+    // outlive the call
+    let n = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            count as _,
+            libc::MSG_WAITFORONE as _,
+            ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for i in 0..n as usize {
+        let name = mem::replace(&mut names[i], SockAddrStorage::zeroed());
+        // SAFETY: the kernel filled in the address and its length
+        let from = unsafe { SockAddr::new(name, headers[i].msg_hdr.msg_namelen) };
+        received[i] = (headers[i].msg_len as usize, from.as_socket());
+    }
+    Ok(n as usize)
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    connect, doh,
+    ids::Ids,
+    message::{self, Message, QType},
+    progress, trace,
+    transport::{self, Conn},
+    workload::Workload,
+    Args, Handler, Query, WorkerStatus,
+};
+
+/// A query in the window
+pub struct Pending {
+    /// Time the query was scheduled to be sent, which its latency is
+    /// measured from
+    pub start: Instant,
+    /// Time it actually went out, which its timeout runs from. Later than
+    /// `start` when --qps has built up a backlog.
+    pub sent: Instant,
+    pub qtype: QType,
+    pub qname: String,
+}
+
+/// Queries sent on one connection that are neither answered nor timed out yet
+pub struct Window {
+    pub pending: HashMap<u16, Pending>,
+    /// Send order, so the oldest queries can be expired first. Entries that
+    /// have already been answered are skipped when they reach the front.
+    order: VecDeque<(u16, Instant)>,
+    /// Set by the sender once it has issued all of its queries
+    pub finished: bool,
+    /// Number of truncated answers waiting for or in their TCP retry, with
+    /// --tcp-fallback. Their queries still count as outstanding.
+    retrying: Option<Arc<AtomicUsize>>,
+}
+
+impl Window {
+    pub fn new() -> Window {
+        Window {
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+            finished: false,
+            retrying: None,
+        }
+    }
+
+    /// Queries sent and not finished yet, including those being retried
+    pub fn outstanding(&self) -> usize {
+        self.pending.len() + self.retrying()
+    }
+
+    /// Queries being retried over TCP
+    pub fn retrying(&self) -> usize {
+        self.retrying
+            .as_ref()
+            .map_or(0, |retrying| retrying.load(Ordering::SeqCst))
+    }
+
+    /// Adds a query that is about to be sent under an ID not in use yet and
+    /// returns the ID
+    pub fn add(
+        &mut self,
+        ids: &mut Ids,
+        rng: &mut impl Rng,
+        start: Instant,
+        qtype: QType,
+        qname: &str,
+    ) -> u16 {
+        let id = ids.next(rng, |id| self.pending.contains_key(&id));
+        let sent = Instant::now();
+        self.pending.insert(
+            id,
+            Pending {
+                start,
+                sent,
+                qtype,
+                qname: qname.to_string(),
+            },
+        );
+        self.order.push_back((id, sent));
+        id
+    }
+
+    /// Takes out the query that `packet` answers, if it is an answer to one.
+    /// A truncated answer counts as retrying from here when retries are on.
+    pub fn answered<'a>(
+        &mut self,
+        packet: &'a [u8],
+        qclass: u16,
+    ) -> Option<(u16, Pending, Message<'a>)> {
+        let v = message::parse(packet)?;
+        let query = self.pending.get(&v.id)?;
+        if !v.matches(v.id, &query.qname, query.qtype, qclass) {
+            return None;
+        }
+        if let (true, Some(retrying)) = (v.truncated, &self.retrying) {
+            retrying.fetch_add(1, Ordering::SeqCst);
+        }
+        Some((v.id, self.pending.remove(&v.id).unwrap(), v))
+    }
+
+    /// Takes out the queries that have waited `timeout` or longer
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(u16, Pending)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(sent) = self.oldest() {
+            if now.duration_since(sent) < timeout {
+                break;
+            }
+            let (id, _) = self.order.pop_front().unwrap();
+            expired.push((id, self.pending.remove(&id).unwrap()));
+        }
+        expired
+    }
+
+    /// When the oldest query still waiting was sent
+    pub fn oldest(&mut self) -> Option<Instant> {
+        while let Some(&(id, sent)) = self.order.front() {
+            if self
+                .pending
+                .get(&id)
+                .is_some_and(|pending| pending.sent == sent)
+            {
+                return Some(sent);
+            }
+            self.order.pop_front();
+        }
+        None
+    }
+
+    /// Takes out every query, for when the connection is lost
+    pub fn drain(&mut self) -> Vec<(u16, Pending)> {
+        self.order.clear();
+        self.pending.drain().collect()
+    }
+}
+
+/// Reports the outcome of a query that left the window
+pub fn finish(handler: &Handler, id: u16, query: Pending, status: WorkerStatus) {
+    let status = trace::wrap(
+        handler.tracing,
+        handler.worker,
+        id,
+        &query.qname,
+        query.start,
+        status,
+    );
+    handler.tx.send((status, thread::current().id())).unwrap();
+}
+
+/// Asks truncated queries again over TCP with --tcp-fallback, one after
+/// another on a thread of its own, so that a slow TCP exchange does not hold
+/// up the answers still arriving over UDP
+pub struct Retries {
+    queue: Sender<(u16, Pending)>,
+    worker: JoinHandle<()>,
+}
+
+impl Retries {
+    /// Starts retrying the truncated answers of `window`, if --tcp-fallback
+    /// is on. `done` is called each time a retry is over and its query no
+    /// longer counts as outstanding.
+    pub fn start(
+        handler: &Handler,
+        window: &mut Window,
+        done: impl Fn() + Send + 'static,
+    ) -> Option<Retries> {
+        handler.fallback.as_ref()?;
+        let retrying = Arc::new(AtomicUsize::new(0));
+        window.retrying = Some(retrying.clone());
+        let handler = handler.clone();
+        let (queue, rx) = mpsc::channel::<(u16, Pending)>();
+        let worker = thread::spawn(move || {
+            for (id, query) in rx {
+                let status = handler.retry(&Query {
+                    id,
+                    qname: &query.qname,
+                    qtype: query.qtype,
+                    index: None,
+                    start: query.start,
+                });
+                finish(&handler, id, query, status);
+                retrying.fetch_sub(1, Ordering::SeqCst);
+                done();
+            }
+        });
+        Some(Retries { queue, worker })
+    }
+
+    /// Waits for the retries still queued
+    pub fn finish(self) {
+        drop(self.queue);
+        self.worker.join().unwrap();
+    }
+}
+
+/// Reports an answered query. A truncated one is asked again over TCP first
+/// with --tcp-fallback.
+pub fn complete(
+    handler: &Handler,
+    id: u16,
+    query: Pending,
+    v: &Message,
+    retries: Option<&Retries>,
+) {
+    if v.truncated {
+        handler.truncated(query.qtype);
+        if let Some(retries) = retries {
+            retries.queue.send((id, query)).unwrap();
+            return;
+        }
+    }
+    let status = handler.answer(
+        &Query {
+            id,
+            qname: &query.qname,
+            qtype: query.qtype,
+            index: None,
+            start: query.start,
+        },
+        v,
+        false,
+    );
+    finish(handler, id, query, status);
+}
+
+struct InFlight {
+    window: Mutex<Window>,
+    /// Signalled whenever a query leaves the window
+    freed: Condvar,
+}
+
+/// Starts a sender and a receiver thread sharing one connection, keeping up
+/// to `--inflight` queries outstanding at once, truncated ones being retried
+/// included.
+pub fn spawn(
+    mut conn: Box<dyn Conn>,
+    args: &Args,
+    mut workload: Workload,
+    handler: Handler,
+) -> Vec<JoinHandle<()>> {
+    let inflight = Arc::new(InFlight {
+        window: Mutex::new(Window::new()),
+        freed: Condvar::new(),
+    });
+    let limit = args.inflight as usize;
+    let random_ids = args.random_ids;
+    let timeout = handler.timeout;
+    let debug = handler.debug;
+    let batch = args.batch as usize;
+    let retries = {
+        let freed = inflight.clone();
+        let mut window = inflight.window.lock().unwrap();
+        Retries::start(&handler, &mut window, move || {
+            let _window = freed.window.lock().unwrap();
+            freed.freed.notify_one();
+        })
+    };
+
+    let sender = {
+        let mut sender = conn.try_clone().unwrap();
+        let inflight = inflight.clone();
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            let mut ids = Ids::new(random_ids);
+            let mut wires = vec![Vec::new(); batch];
+            // ID and type of each query in `wires`
+            let mut queued = Vec::with_capacity(batch);
+            loop {
+                // A batch takes the queries that are due already and fit into
+                // the window; only its first query waits for either
+                queued.clear();
+                while queued.is_empty()
+                    || queued.len() < batch
+                        && workload.ready()
+                        && inflight.window.lock().unwrap().outstanding() < limit
+                {
+                    let Some((start, qname, query_type, index)) = workload.next() else {
+                        break;
+                    };
+                    if !connect(sender.as_mut(), &handler.tx) {
+                        continue;
+                    }
+                    if debug >= 2 {
+                        let line = format!("select domain: {} {}", qname, query_type);
+                        progress(&line, handler.to_stderr);
+                    }
+
+                    let mut window = inflight.window.lock().unwrap();
+                    while window.outstanding() >= limit {
+                        window = inflight.freed.wait(window).unwrap();
+                    }
+                    let id = window.add(&mut ids, &mut rng, start, query_type, qname);
+                    drop(window);
+
+                    let wire = &mut wires[queued.len()];
+                    handler
+                        .encoder
+                        .write(wire, id, qname, query_type, index, &mut rng);
+                    queued.push((id, query_type));
+                }
+                if queued.is_empty() {
+                    break;
+                }
+
+                let sent = sender.send_batch(&wires[..queued.len()]).unwrap_or(0);
+                for (k, &(id, query_type)) in queued.iter().enumerate() {
+                    if k < sent {
+                        handler
+                            .tx
+                            .send((WorkerStatus::Sent(query_type), thread::current().id()))
+                            .unwrap();
+                        continue;
+                    }
+                    // Otherwise it has already timed out
+                    if let Some(query) = inflight.window.lock().unwrap().pending.remove(&id) {
+                        finish(&handler, id, query, WorkerStatus::Failed(query_type));
+                    }
+                }
+            }
+            inflight.window.lock().unwrap().finished = true;
+        })
+    };
+
+    let receiver = thread::spawn(move || {
+        let tick = timeout.min(Duration::from_millis(10));
+        let mut packets = vec![vec![0; 65535]; batch];
+        let mut lens = vec![None; batch];
+        let qclass = handler.encoder.qclass;
+        loop {
+            // Read timeouts just drive the expiry check below. Other errors
+            // that cannot be tied to a single query are ignored; the affected
+            // queries simply time out.
+            match conn.recv_batch(&mut packets, &mut lens, Instant::now() + tick) {
+                Ok(received) => {
+                    for (packet, len) in packets.iter().zip(&lens).take(received) {
+                        let answered = len.and_then(|len| {
+                            let mut window = inflight.window.lock().unwrap();
+                            window.answered(&packet[..len], qclass)
+                        });
+                        match answered {
+                            Some((id, query, v)) => {
+                                inflight.freed.notify_one();
+                                complete(&handler, id, query, &v, retries.as_ref());
+                            }
+                            None => handler.stray(),
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                    let lost = inflight.window.lock().unwrap().drain();
+                    inflight.freed.notify_all();
+                    for (id, query) in lost {
+                        let status = WorkerStatus::Failed(query.qtype);
+                        finish(&handler, id, query, status);
+                    }
+                }
+                Err(e) if transport::is_foreign(&e) => handler.stray(),
+                Err(e) => {
+                    if let Some(failure) = doh::failure(&e) {
+                        let query = inflight.window.lock().unwrap().pending.remove(&failure.id);
+                        match query {
+                            Some(query) => {
+                                inflight.freed.notify_one();
+                                let status = match failure.status {
+                                    Some(status) => WorkerStatus::HttpStatus(query.qtype, status),
+                                    None => WorkerStatus::Failed(query.qtype),
+                                };
+                                finish(&handler, failure.id, query, status);
+                            }
+                            None => handler.stray(),
+                        }
+                    }
+                }
+            }
+
+            let mut window = inflight.window.lock().unwrap();
+            let expired = window.expire(timeout);
+            if !expired.is_empty() {
+                inflight.freed.notify_all();
+            }
+            for (id, query) in expired {
+                let status = WorkerStatus::Timeout(query.qtype);
+                finish(&handler, id, query, status);
+            }
+            if window.finished && window.pending.is_empty() {
+                break;
+            }
+        }
+        if let Some(retries) = retries {
+            retries.finish();
+        }
+        handler
+            .tx
+            .send((WorkerStatus::AllFinished, thread::current().id()))
+            .unwrap();
+    });
+
+    vec![sender, receiver]
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{encode::Encoder, Args};
+
+    /// The server's answer to the query `id` for `qname`: the query with QR
+    /// set
+    fn answer(id: u16, qname: &str) -> Vec<u8> {
+        let encoder = Encoder::new(&Args::parse_from(["dnsbench"]));
+        let mut packet = encoder.encode(id, qname, QType(1), &mut rand::thread_rng());
+        packet[2] |= 0x80;
+        packet
+    }
+
+    fn window(qnames: &[&str], start: Instant) -> (Window, Vec<u16>) {
+        let mut window = Window::new();
+        let mut ids = Ids::new(false);
+        let mut rng = rand::thread_rng();
+        let added = qnames
+            .iter()
+            .map(|qname| window.add(&mut ids, &mut rng, start, QType(1), qname))
+            .collect();
+        (window, added)
+    }
+
+    #[test]
+    fn answered() {
+        let (mut window, ids) = window(&["a.example", "b.example"], Instant::now());
+        assert_ne!(ids[0], ids[1]);
+
+        // Another name, or the query itself rather than an answer
+        assert!(window.answered(&answer(ids[1], "a.example"), 1).is_none());
+        let mut query = answer(ids[1], "b.example");
+        query[2] &= !0x80;
+        assert!(window.answered(&query, 1).is_none());
+        assert_eq!(window.pending.len(), 2);
+
+        let (id, pending, _) = window.answered(&answer(ids[1], "b.example"), 1).unwrap();
+        assert_eq!((id, pending.qname.as_str()), (ids[1], "b.example"));
+        // Answered only once
+        assert!(window.answered(&answer(ids[1], "b.example"), 1).is_none());
+        assert_eq!(window.pending.len(), 1);
+    }
+
+    #[test]
+    fn expire_oldest_first() {
+        let (mut window, ids) = window(&["a.example", "b.example", "c.example"], Instant::now());
+        assert!(window.expire(Duration::from_secs(3600)).is_empty());
+        window.answered(&answer(ids[0], "a.example"), 1).unwrap();
+        let expired: Vec<u16> = window
+            .expire(Duration::ZERO)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(expired, ids[1..]);
+        assert!(window.pending.is_empty());
+        assert!(window.oldest().is_none());
+    }
+
+    #[test]
+    fn timeout_runs_from_send() {
+        // Scheduled long ago, as with a --qps backlog, but only sent now
+        let start = Instant::now() - Duration::from_secs(10);
+        let (mut window, _) = window(&["a.example"], start);
+        assert!(window.expire(Duration::from_secs(1)).is_empty());
+        let pending = &window.pending.values().next().unwrap();
+        assert_eq!(pending.start, start);
+        assert!(window.oldest().unwrap() > start);
+    }
+
+    #[test]
+    fn oldest_skips_answered() {
+        let (mut window, ids) = window(&["a.example"], Instant::now());
+        let first = window.oldest().unwrap();
+        let mut more = Ids::new(false);
+        let second = window.add(
+            &mut more,
+            &mut rand::thread_rng(),
+            Instant::now(),
+            QType(1),
+            "b.example",
+        );
+        window.answered(&answer(ids[0], "a.example"), 1).unwrap();
+        let oldest = window.oldest().unwrap();
+        assert!(oldest >= first);
+        assert_eq!(oldest, window.pending[&second].sent);
+    }
+
+    #[test]
+    fn truncated_stays_outstanding() {
+        let (mut window, ids) = window(&["a.example", "b.example"], Instant::now());
+        let retrying = Arc::new(AtomicUsize::new(0));
+        window.retrying = Some(retrying.clone());
+        let mut truncated = answer(ids[0], "a.example");
+        truncated[2] |= 0x02;
+        window.answered(&truncated, 1).unwrap();
+        window.answered(&answer(ids[1], "b.example"), 1).unwrap();
+        assert!(window.pending.is_empty());
+        assert_eq!((window.retrying(), window.outstanding()), (1, 1));
+
+        retrying.fetch_sub(1, Ordering::SeqCst);
+        assert_eq!(window.outstanding(), 0);
+    }
+
+    #[test]
+    fn drain() {
+        let (mut window, _) = window(&["a.example", "b.example"], Instant::now());
+        assert_eq!(window.drain().len(), 2);
+        assert!(window.pending.is_empty());
+        assert!(window.oldest().is_none());
+    }
+}
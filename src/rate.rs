@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use rand::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Arrival {
+    /// Evenly spaced sends
+    Fixed,
+    /// Exponentially distributed gaps between sends
+    Poisson,
+}
+
+/// Parses a --qps value: a positive number whose send interval fits in a
+/// `Duration`
+pub fn parse_qps(s: &str) -> Result<f64, String> {
+    let qps: f64 = s.parse().map_err(|_| format!("invalid rate `{}`", s))?;
+    if !(qps > 0.0 && qps.is_finite()) || Duration::try_from_secs_f64(1.0 / qps).is_err() {
+        return Err(format!("rate `{}` must be a positive number", s));
+    }
+    Ok(qps)
+}
+
+/// Open-loop send timetable for one worker thread.
+///
+/// Send times are computed from the start of the run rather than from the
+/// previous response, so a slow server delays the actual sends but never
+/// the schedule itself.
+pub struct Pacer {
+    next: Instant,
+    interval: f64,
+    arrival: Arrival,
+}
+
+impl Pacer {
+    /// `qps` is the rate for this thread alone; `phase` in [0, 1) staggers
+    /// the fixed timetables of different threads.
+    pub fn new(qps: f64, arrival: Arrival, phase: f64) -> Self {
+        let interval = 1.0 / qps;
+        Pacer {
+            next: Instant::now() + Duration::from_secs_f64(interval * phase),
+            interval,
+            arrival,
+        }
+    }
+
+    /// When the next send is scheduled
+    pub fn due(&self) -> Instant {
+        self.next
+    }
+
+    /// Sleeps until the next scheduled send and returns the intended send
+    /// time, which latency should be measured from.
+    pub fn wait(&mut self, rng: &mut impl Rng) -> Instant {
+        let due = self.next;
+        let gap = match self.arrival {
+            Arrival::Fixed => self.interval,
+            Arrival::Poisson => -(1.0 - rng.gen::<f64>()).ln() * self.interval,
+        };
+        self.next += Duration::from_secs_f64(gap);
+
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        due
+    }
+}
@@ -0,0 +1,450 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    edns,
+    message::Rcode,
+    stats::{Latency, Totals},
+    Run,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Output {
+    /// Human-readable summary lines
+    Text,
+    /// One JSON document with the configuration and all results
+    Json,
+    /// The JSON document flattened into a header row and a value row
+    Csv,
+}
+
+/// Prints the human-readable summary of a finished run
+pub fn text(run: &Run) {
+    let (args, totals, elapsed) = (&run.args, &run.totals, run.elapsed);
+    println!(
+        "ALLDONE sent: {}, success: {}, timeout: {}, failed: {}, connect failed: {}, thread finished: {}, percent: 100%, time: {}s",
+        totals.sent,
+        totals.success,
+        totals.timeout,
+        totals.failed,
+        totals.connect_failed,
+        args.threads,
+        elapsed.as_secs_f32()
+    );
+    if totals.stray > 0 {
+        println!(
+            "STRAY {} responses matched no outstanding query",
+            totals.stray
+        );
+    }
+    if totals.truncated > 0 || args.tcp_fallback {
+        println!(
+            "TRUNCATED {} answers, truncation rate: {}, answered over TCP: {}, fallback rate: {}",
+            totals.truncated,
+            percent(totals.truncated, totals.sent),
+            totals.over_tcp,
+            percent(totals.over_tcp, totals.sent)
+        );
+    }
+    if let Some(qps) = args.qps {
+        println!(
+            "RATE target: {:.1} qps, achieved: {:.1} qps",
+            qps,
+            totals.sent as f64 / elapsed.as_secs_f64()
+        );
+    }
+    if !totals.http_status.is_empty() {
+        let counts: Vec<String> = totals
+            .http_status
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        println!("HTTP STATUS {}", counts.join(", "));
+    }
+    let counts: Vec<String> = Rcode::ALL
+        .iter()
+        .map(|rcode| format!("{}: {}", rcode, totals.rcodes.get(rcode).unwrap_or(&0)))
+        .collect();
+    println!("RCODE {}", counts.join(", "));
+    println!("LATENCY {}", totals.latency.summary());
+    println!(
+        "CPU user: {:.3}s, system: {:.3}s, {:.1}% of one core, {:.2}us per query",
+        run.cpu.user.as_secs_f64(),
+        run.cpu.system.as_secs_f64(),
+        run.cpu.total().as_secs_f64() / elapsed.as_secs_f64() * 100.0,
+        per_query(run)
+    );
+    if edns::enabled(args) {
+        let echoes = &totals.echoes;
+        println!(
+            "EDNS answers: {}, with OPT: {}, DO: {}, ECS: {}, cookie: {}, NSID: {}, padding: {}",
+            echoes.answers,
+            echoes.opt,
+            echoes.dnssec,
+            echoes.ecs,
+            echoes.cookie,
+            echoes.nsid,
+            echoes.padding
+        );
+    }
+    if args.reference.is_some() || args.expected.is_some() {
+        let validation = &totals.validation;
+        println!(
+            "VALIDATION match: {}, missing: {}, mismatch: {}, unknown: {}, ttl anomalies: {}",
+            validation.matched,
+            validation.missing,
+            validation.mismatch,
+            validation.unknown,
+            validation.ttl_anomalies
+        );
+    }
+    if totals.by_type.len() > 1 {
+        for (qtype, stats) in &totals.by_type {
+            println!(
+                "TYPE {} sent: {}, success: {}, timeout: {}, failed: {}, {}",
+                qtype,
+                stats.sent,
+                stats.success,
+                stats.timeout,
+                stats.failed,
+                stats.latency.summary()
+            );
+        }
+    }
+    if totals.connections > 0 {
+        println!(
+            "HANDSHAKE connections: {}, resumed: {}, {}",
+            totals.connections,
+            totals.resumed,
+            totals.handshake.summary()
+        );
+    }
+}
+
+/// The run configuration and all results as one JSON document
+pub fn json(run: &Run) -> Value {
+    let (args, totals) = (&run.args, &run.totals);
+    let elapsed = run.elapsed.as_secs_f64();
+    let mut types = Map::new();
+    for (qtype, stats) in &totals.by_type {
+        types.insert(
+            qtype.to_string(),
+            json!({
+                "sent": stats.sent,
+                "success": stats.success,
+                "timeout": stats.timeout,
+                "failed": stats.failed,
+                "latency_ms": latency(&stats.latency),
+            }),
+        );
+    }
+
+    json!({
+        "config": {
+            "server": args.server.to_string(),
+            "seed": args.seed,
+            "source": args.source.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+            "source_port": args.source_port.map(|(low, high)| format!("{}-{}", low, high)),
+            "transport": name(&args.transport),
+            "threads": args.threads,
+            "number": args.number,
+            "duration_s": args.duration.map(|duration| duration.as_secs_f64()),
+            "warmup_s": args.warmup.map(|warmup| warmup.as_secs_f64()),
+            "inflight": args.inflight,
+            "batch": args.batch,
+            "io": name(&args.io),
+            "domains": args.domains,
+            "record": args.record.to_string(),
+            "class": args.class,
+            "case": name(&args.case),
+            "replay": args.replay,
+            "timeout_ms": args.timeout,
+            "random_ids": args.random_ids,
+            "tcp_fallback": args.tcp_fallback,
+            "qps": args.qps,
+            "arrival": name(&args.arrival),
+            "tls_name": args.tls_name,
+            "ca_file": args.ca_file,
+            "insecure": args.insecure,
+            "no_resumption": args.no_resumption,
+            "doh_method": name(&args.doh_method),
+            "doh_path": args.doh_path,
+            "edns": edns::enabled(args),
+            "bufsize": args.bufsize,
+            "dnssec": args.dnssec,
+            "ecs": args.ecs.map(|(ip, prefix)| format!("{}/{}", ip, prefix)),
+            "cookie": args.cookie,
+            "nsid": args.nsid,
+            "padding": args.padding,
+            "reference": args.reference.map(|reference| reference.to_string()),
+            "expected": args.expected,
+        },
+        "results": {
+            "elapsed_s": elapsed,
+            "sent": totals.sent,
+            "success": totals.success,
+            "timeout": totals.timeout,
+            "failed": totals.failed,
+            "connect_failed": totals.connect_failed,
+            "stray": totals.stray,
+            "sent_qps": totals.sent as f64 / elapsed,
+            "success_qps": totals.success as f64 / elapsed,
+            "success_rate": ratio(totals.success, totals.sent),
+        },
+        "rcode": Rcode::ALL
+            .iter()
+            .map(|rcode| (rcode.to_string(), json!(totals.rcodes.get(rcode).unwrap_or(&0))))
+            .collect::<Map<_, _>>(),
+        "http_status": totals
+            .http_status
+            .iter()
+            .map(|(status, count)| (status.to_string(), json!(count)))
+            .collect::<Map<_, _>>(),
+        "latency_ms": latency(&totals.latency),
+        "cpu": {
+            "user_s": run.cpu.user.as_secs_f64(),
+            "system_s": run.cpu.system.as_secs_f64(),
+            "cores": run.cpu.total().as_secs_f64() / elapsed,
+            "us_per_query": per_query(run),
+        },
+        "truncation": {
+            "truncated": totals.truncated,
+            "over_tcp": totals.over_tcp,
+            "truncation_rate": ratio(totals.truncated, totals.sent),
+            "fallback_rate": ratio(totals.over_tcp, totals.sent),
+        },
+        "handshake": {
+            "connections": totals.connections,
+            "resumed": totals.resumed,
+            "latency_ms": latency(&totals.handshake),
+        },
+        "types": types,
+        "edns": edns::enabled(args).then(|| {
+            let echoes = &totals.echoes;
+            json!({
+                "answers": echoes.answers,
+                "opt": echoes.opt,
+                "dnssec": echoes.dnssec,
+                "ecs": echoes.ecs,
+                "cookie": echoes.cookie,
+                "nsid": echoes.nsid,
+                "padding": echoes.padding,
+            })
+        }),
+        "validation": (args.reference.is_some() || args.expected.is_some()).then(|| {
+            let validation = &totals.validation;
+            json!({
+                "match": validation.matched,
+                "missing": validation.missing,
+                "mismatch": validation.mismatch,
+                "unknown": validation.unknown,
+                "ttl_anomalies": validation.ttl_anomalies,
+            })
+        }),
+        "intervals": run.intervals,
+    })
+}
+
+/// One line of statistics for the part of the run between `from` and `to`.
+/// Queries are counted as sent when sent, but the success rate and latency
+/// are those of the queries that finished in the interval.
+pub fn interval_line(from: Duration, to: Duration, window: &Totals) -> String {
+    let secs = (to - from).as_secs_f64();
+    format!(
+        "INTERVAL {:.1}s-{:.1}s sent: {}, qps: {:.1}, success: {}, success rate: {}, timeout: {}, failed: {}, {}",
+        from.as_secs_f64(),
+        to.as_secs_f64(),
+        window.sent,
+        window.sent as f64 / secs,
+        window.success,
+        percent(window.success, finished(window)),
+        window.timeout,
+        window.failed,
+        window.latency.summary()
+    )
+}
+
+pub fn interval_json(from: Duration, to: Duration, window: &Totals) -> Value {
+    let secs = (to - from).as_secs_f64();
+    json!({
+        "start_s": from.as_secs_f64(),
+        "end_s": to.as_secs_f64(),
+        "sent": window.sent,
+        "success": window.success,
+        "timeout": window.timeout,
+        "failed": window.failed,
+        "sent_qps": window.sent as f64 / secs,
+        "success_rate": ratio(window.success, finished(window)),
+        "latency_ms": latency(&window.latency),
+    })
+}
+
+/// Client CPU time per query sent, in microseconds
+fn per_query(run: &Run) -> f64 {
+    run.cpu.total().as_secs_f64() * 1e6 / run.totals.sent.max(1) as f64
+}
+
+fn finished(window: &Totals) -> u64 {
+    window.success + window.timeout + window.failed + window.http_status.values().sum::<u64>()
+}
+
+/// Flattens JSON documents into CSV, one row per document and one column
+/// per leaf named by its dotted path. The columns are those of all
+/// documents, as the query types and HTTP statuses seen differ between
+/// servers; a document without one leaves it empty.
+pub fn csv(docs: &[Value]) -> String {
+    let rows: Vec<Vec<(String, String)>> = docs
+        .iter()
+        .map(|doc| {
+            let mut columns = Vec::new();
+            flatten("", doc, &mut columns);
+            columns
+        })
+        .collect();
+    let mut header: Vec<&str> = Vec::new();
+    for (key, _) in rows.iter().flatten() {
+        if !header.contains(&key.as_str()) {
+            header.push(key);
+        }
+    }
+
+    let mut out = header
+        .iter()
+        .map(|key| quote(key))
+        .collect::<Vec<_>>()
+        .join(",");
+    out.push('\n');
+    for row in &rows {
+        let values: Vec<String> = header
+            .iter()
+            .map(|key| {
+                row.iter()
+                    .find(|(column, _)| column == key)
+                    .map_or(String::new(), |(_, value)| quote(value))
+            })
+            .collect();
+        out += &values.join(",");
+        out.push('\n');
+    }
+    out
+}
+
+fn flatten(prefix: &str, value: &Value, columns: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = match prefix {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", prefix, key),
+                };
+                flatten(&path, value, columns);
+            }
+        }
+        // A series such as the intervals does not fit into one row
+        Value::Array(_) => {}
+        Value::Null => columns.push((prefix.to_string(), String::new())),
+        Value::String(s) => columns.push((prefix.to_string(), s.clone())),
+        _ => columns.push((prefix.to_string(), value.to_string())),
+    }
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn latency(latency: &Latency) -> Value {
+    let mut map = Map::new();
+    map.insert("count".to_string(), json!(latency.count()));
+    // Null without samples, so that every document has the same fields
+    let points = latency.points();
+    for (i, name) in Latency::POINTS.iter().enumerate() {
+        let ms = points.map(|points| points[i].1);
+        map.insert(name.replace('.', ""), json!(ms));
+    }
+    Value::Object(map)
+}
+
+/// Prints the main figures of several servers side by side
+pub fn compare(runs: &[Run]) {
+    let width = runs
+        .iter()
+        .map(|run| run.args.server.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let mut header = format!("{:<width$} {:>8} {:>8}", "SERVER", "sent", "success");
+    for rcode in Rcode::ALL {
+        header += &format!(" {:>8}", rcode.to_string());
+    }
+    for name in POINTS {
+        header += &format!(" {:>10}", name);
+    }
+    println!("COMPARE");
+    println!("{}", header);
+
+    for run in runs {
+        let totals = &run.totals;
+        let mut row = format!(
+            "{:<width$} {:>8} {:>8}",
+            run.args.server.to_string(),
+            totals.sent,
+            percent(totals.success, totals.sent)
+        );
+        for rcode in Rcode::ALL {
+            row += &format!(" {:>8}", totals.rcodes.get(&rcode).unwrap_or(&0));
+        }
+        let points = totals.latency.points();
+        for name in POINTS {
+            let ms = points
+                .iter()
+                .flatten()
+                .find(|(point, _)| *point == name)
+                .map(|(_, ms)| *ms);
+            row += &match ms {
+                Some(ms) => format!(" {:>8.3}ms", ms),
+                None => format!(" {:>10}", "-"),
+            };
+        }
+        println!("{}", row);
+    }
+}
+
+/// Latency points shown by `compare`
+const POINTS: [&str; 5] = ["p50", "p90", "p99", "p99.9", "max"];
+
+fn percent(part: u64, whole: u64) -> String {
+    match ratio(part, whole) {
+        Some(rate) => format!("{:.1}%", rate * 100.0),
+        None => "-".to_string(),
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 / whole as f64)
+}
+
+pub fn name<T: ValueEnum>(value: &T) -> String {
+    value.to_possible_value().unwrap().get_name().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_columns_from_all_documents() {
+        let docs = [
+            json!({"server": "a", "types": {"A": 1}}),
+            json!({"server": "b", "types": {"AAAA": 2}, "latency": {"p50": null}}),
+        ];
+        assert_eq!(
+            csv(&docs),
+            "server,types.A,types.AAAA,latency.p50\na,1,,\nb,,2,\n"
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    message::{self, QType, Rcode},
+    mix::Mix,
+    parse_duration,
+};
+
+/// Options of `dnsbench serve`
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServeArgs {
+    /// Address to answer on, over both UDP and TCP
+    #[clap(short, long, default_value = "127.0.0.1:5353")]
+    listen: SocketAddr,
+
+    /// Number of threads reading UDP queries
+    #[clap(short = 'p', long, default_value = "1")]
+    threads: u32,
+
+    /// Time to wait before answering: a fixed `5ms`, uniform over a range
+    /// such as `1ms-10ms`, or exponential with a mean such as `exp:5ms`
+    #[clap(long, default_value = "0ms")]
+    delay: Delay,
+
+    /// Share of queries left unanswered, from 0 to 1
+    #[clap(long, default_value = "0", value_parser = parse_share)]
+    drop: f64,
+
+    /// Response codes to answer with and their relative weights, e.g.
+    /// `NOERROR=90,NXDOMAIN=9,SERVFAIL=1`. NOERROR answers A and AAAA
+    /// queries with one record and other types with no data.
+    #[clap(long, default_value = "NOERROR")]
+    rcodes: Mix<Rcode>,
+
+    /// Share of UDP answers sent truncated, with the TC bit set and no
+    /// records. Answers over TCP are never truncated.
+    #[clap(long, default_value = "0", value_parser = parse_share)]
+    truncate: f64,
+
+    /// Share of answers sent with a wrong ID instead of the query's
+    #[clap(long, default_value = "0", value_parser = parse_share)]
+    wrong_id: f64,
+}
+
+/// Parses a share of queries, from 0 to 1
+fn parse_share(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+        _ => Err(format!("`{}` is not a share from 0 to 1", s)),
+    }
+}
+
+/// How long answers are held back
+#[derive(Clone, Copy, Debug)]
+pub enum Delay {
+    Fixed(Duration),
+    Uniform(Duration, Duration),
+    Exponential(Duration),
+}
+
+impl Delay {
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match *self {
+            Delay::Fixed(delay) => delay,
+            Delay::Uniform(low, high) => rng.gen_range(low..=high),
+            Delay::Exponential(mean) => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(mean) = s.strip_prefix("exp:") {
+            return Ok(Delay::Exponential(parse_duration(mean)?));
+        }
+        match s.split_once('-') {
+            Some((low, high)) => {
+                let (low, high) = (parse_duration(low)?, parse_duration(high)?);
+                if low > high {
+                    return Err(format!("empty delay range `{}`", s));
+                }
+                Ok(Delay::Uniform(low, high))
+            }
+            None => Ok(Delay::Fixed(parse_duration(s)?)),
+        }
+    }
+}
+
+/// What happened to the queries, printed once a second
+#[derive(Default)]
+struct Counts {
+    queries: AtomicU64,
+    answered: AtomicU64,
+    dropped: AtomicU64,
+    truncated: AtomicU64,
+    wrong_id: AtomicU64,
+    malformed: AtomicU64,
+}
+
+/// Where an answer goes
+enum Peer {
+    Udp(Arc<UdpSocket>, SocketAddr),
+    /// Writes are serialized, so that answers on one connection cannot
+    /// interleave
+    Tcp(Arc<Mutex<TcpStream>>),
+}
+
+impl Peer {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Peer::Udp(socket, addr) => socket.send_to(packet, addr).map(|_| ()),
+            Peer::Tcp(stream) => {
+                let mut framed = (packet.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(packet);
+                stream.lock().unwrap().write_all(&framed)
+            }
+        }
+    }
+}
+
+/// An answer waiting for its delay to pass
+struct Delayed {
+    due: Instant,
+    packet: Vec<u8>,
+    peer: Peer,
+}
+
+// Ordered so that the `BinaryHeap` pops the earliest answer first
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.due.cmp(&self.due)
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due
+    }
+}
+
+impl Eq for Delayed {}
+
+/// Answers queries until killed. Meant for calibrating the client against a
+/// server of known behaviour, not as a real name server.
+pub fn run(args: &ServeArgs) {
+    let (listen, counts) = start(args);
+    println!("SERVE listening on {} over UDP and TCP", listen);
+
+    let start = Instant::now();
+    let mut last = 0;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let queries = counts.queries.load(Relaxed);
+        if queries == last {
+            continue;
+        }
+        println!(
+            "SERVE {:.0}s queries: {}, qps: {}, answered: {}, dropped: {}, truncated: {}, wrong id: {}, malformed: {}",
+            start.elapsed().as_secs_f64(),
+            queries,
+            queries - last,
+            counts.answered.load(Relaxed),
+            counts.dropped.load(Relaxed),
+            counts.truncated.load(Relaxed),
+            counts.wrong_id.load(Relaxed),
+            counts.malformed.load(Relaxed)
+        );
+        last = queries;
+    }
+}
+
+/// Starts answering on the threads of the server and returns the address
+/// it answers on, which has a port even if --listen has port 0
+fn start(args: &ServeArgs) -> (SocketAddr, Arc<Counts>) {
+    let counts = Arc::new(Counts::default());
+    let (tx, rx) = channel();
+    thread::spawn(move || send_delayed(rx));
+
+    let udp = Arc::new(UdpSocket::bind(args.listen).unwrap());
+    let listen = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(listen).unwrap();
+
+    for _ in 0..args.threads {
+        let (udp, args, counts, tx) = (udp.clone(), args.clone(), counts.clone(), tx.clone());
+        thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            let mut packet = [0; 4096];
+            loop {
+                let Ok((len, from)) = udp.recv_from(&mut packet) else {
+                    continue;
+                };
+                let peer = Peer::Udp(udp.clone(), from);
+                respond(&args, &packet[..len], peer, false, &counts, &tx, &mut rng);
+            }
+        });
+    }
+
+    {
+        let (args, counts) = (args.clone(), counts.clone());
+        thread::spawn(move || {
+            for stream in tcp.incoming().flatten() {
+                let (args, counts, tx) = (args.clone(), counts.clone(), tx.clone());
+                thread::spawn(move || serve_tcp(&args, stream, &counts, &tx));
+            }
+        });
+    }
+    (listen, counts)
+}
+
+/// Reads length-prefixed queries from one TCP client until it hangs up
+fn serve_tcp(args: &ServeArgs, mut stream: TcpStream, counts: &Counts, tx: &Sender<Delayed>) {
+    let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+    let mut rng = rand::thread_rng();
+    let mut len = [0; 2];
+    while stream.read_exact(&mut len).is_ok() {
+        let mut packet = vec![0; u16::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut packet).is_err() {
+            break;
+        }
+        let peer = Peer::Tcp(writer.clone());
+        respond(args, &packet, peer, true, counts, tx, &mut rng);
+    }
+}
+
+fn respond(
+    args: &ServeArgs,
+    query: &[u8],
+    peer: Peer,
+    over_tcp: bool,
+    counts: &Counts,
+    tx: &Sender<Delayed>,
+    rng: &mut impl Rng,
+) {
+    counts.queries.fetch_add(1, Relaxed);
+    let Some(mut packet) = answer(args, query, over_tcp, counts, rng) else {
+        counts.malformed.fetch_add(1, Relaxed);
+        return;
+    };
+    if rng.gen_bool(args.drop) {
+        counts.dropped.fetch_add(1, Relaxed);
+        return;
+    }
+    if rng.gen_bool(args.wrong_id) {
+        let id = u16::from_be_bytes([packet[0], packet[1]]) ^ rng.gen_range(1..=u16::MAX);
+        packet[..2].copy_from_slice(&id.to_be_bytes());
+        counts.wrong_id.fetch_add(1, Relaxed);
+    }
+    counts.answered.fetch_add(1, Relaxed);
+
+    let delay = args.delay.sample(rng);
+    if delay.is_zero() {
+        let _ = peer.send(&packet);
+    } else {
+        tx.send(Delayed {
+            due: Instant::now() + delay,
+            packet,
+            peer,
+        })
+        .unwrap();
+    }
+}
+
+/// Builds the answer to `query`, or None if it is not a query with exactly
+/// one question
+fn answer(
+    args: &ServeArgs,
+    query: &[u8],
+    over_tcp: bool,
+    counts: &Counts,
+    rng: &mut impl Rng,
+) -> Option<Vec<u8>> {
+    let msg = message::parse(query)?;
+    if msg.response || msg.questions.len() != 1 {
+        return None;
+    }
+    let question = &msg.questions[0];
+    let question_end = message::read_name(query, 12)?.1 + 4;
+
+    let rcode = args.rcodes.sample(rng);
+    let truncated = !over_tcp && rng.gen_bool(args.truncate);
+    // QR and AA, with the opcode and RD copied from the query
+    let mut flags = 0x8400 | (u16::from_be_bytes([query[2], query[3]]) & 0x7900);
+    flags |= rcode.code() as u16;
+    if truncated {
+        flags |= 0x0200;
+        counts.truncated.fetch_add(1, Relaxed);
+    }
+
+    let rdata = match question.qtype {
+        _ if truncated || rcode != Rcode::NoError => None,
+        QType(1) => Some(address(&question.name).to_vec()),
+        QType(28) => {
+            let mut rdata = vec![0x20, 0x01, 0x0d, 0xb8];
+            rdata.extend(vec![0; 8]);
+            rdata.extend(address(&question.name));
+            Some(rdata)
+        }
+        _ => None,
+    };
+
+    let mut packet = query[..2].to_vec();
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&(rdata.is_some() as u16).to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&(msg.opt.is_some() as u16).to_be_bytes());
+    packet.extend_from_slice(&query[12..question_end]);
+    if let Some(rdata) = rdata {
+        // The name is a pointer to the question's
+        packet.extend_from_slice(&[0xc0, 0x0c]);
+        packet.extend_from_slice(&question.qtype.0.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&300u32.to_be_bytes());
+        packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&rdata);
+    }
+    if let Some(opt) = &msg.opt {
+        // An OPT without options, with the DO bit of the query
+        packet.push(0);
+        packet.extend_from_slice(&41u16.to_be_bytes());
+        packet.extend_from_slice(&1232u16.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&(if opt.dnssec_ok { 0x8000u16 } else { 0 }).to_be_bytes());
+        packet.extend_from_slice(&0u16.to_be_bytes());
+    }
+    Some(packet)
+}
+
+/// Address bytes derived from the name, so that a name always gets the same
+/// answer
+fn address(name: &str) -> [u8; 4] {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in name.to_ascii_lowercase().bytes() {
+        hash = (hash ^ byte as u32).wrapping_mul(0x01000193);
+    }
+    // Within the 198.18.0.0/15 benchmarking range
+    [
+        198,
+        18 | ((hash >> 16) & 1) as u8,
+        (hash >> 8) as u8,
+        hash as u8,
+    ]
+}
+
+/// Sends held back answers once they are due
+fn send_delayed(rx: Receiver<Delayed>) {
+    let mut queue = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while queue.peek().is_some_and(|next: &Delayed| next.due <= now) {
+            let delayed = queue.pop().unwrap();
+            let _ = delayed.peer.send(&delayed.packet);
+        }
+        let received = match queue.peek() {
+            Some(next) => rx.recv_timeout(next.due - now),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(delayed) => queue.push(delayed),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Instant};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        encode::Encoder,
+        recv_resp, send_req,
+        stats::Totals,
+        transport::{self, Local},
+        Args, Handler, Query, WorkerStatus,
+    };
+
+    #[derive(Parser)]
+    struct Serve {
+        #[clap(flatten)]
+        args: ServeArgs,
+    }
+
+    /// Starts a server with the options `serve` on a free port and sends
+    /// it one query for example.com with `send_req` and `recv_resp`.
+    /// Returns the outcome, the client's counts and the server's.
+    fn exchange(serve: &[&str], client: &[&str]) -> (WorkerStatus, Totals, Arc<Counts>) {
+        let serve = Serve::parse_from(["serve", "--listen", "127.0.0.1:0"].iter().chain(serve));
+        let (listen, counts) = start(&serve.args);
+
+        let mut args = Args::parse_from(["dnsbench", "--timeout", "300"].iter().chain(client));
+        args.server = listen;
+        let (tx, rx) = channel();
+        let handler = Handler {
+            worker: 0,
+            tracing: false,
+            debug: 0,
+            to_stderr: false,
+            timeout: Duration::from_millis(args.timeout),
+            encoder: Encoder::new(&args),
+            validator: None,
+            fallback: None,
+            tx,
+        };
+        let mut conn = transport::open(&args, None, Local::new(&args, 0));
+        conn.connect().unwrap();
+        let query = Query {
+            id: 4660,
+            qname: "example.com",
+            qtype: QType(1),
+            index: None,
+            start: Instant::now(),
+        };
+        let mut rng = rand::thread_rng();
+        let (sent, _) = send_req(
+            conn.as_mut(),
+            &query,
+            &handler.encoder,
+            &mut Vec::new(),
+            &mut rng,
+        );
+        let deadline = Instant::now() + handler.timeout;
+        let outcome = recv_resp(conn.as_mut(), &query, deadline, &handler, &mut [0; 65535]);
+
+        let mut totals = Totals::new();
+        totals.record(&sent);
+        totals.record(&outcome);
+        drop(handler);
+        for (status, _) in rx {
+            totals.record(&status);
+        }
+        (outcome, totals, counts)
+    }
+
+    fn answered(outcome: &WorkerStatus) -> Option<(Rcode, u16)> {
+        match *outcome {
+            WorkerStatus::Success { rcode, answers, .. } => Some((rcode, answers)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn answers_over_udp() {
+        let (outcome, totals, counts) = exchange(&[], &[]);
+        assert_eq!(answered(&outcome), Some((Rcode::NoError, 1)));
+        assert_eq!((totals.sent, totals.success, totals.stray), (1, 1, 0));
+        assert_eq!(counts.queries.load(Relaxed), 1);
+        assert_eq!(counts.answered.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn answers_over_tcp() {
+        let (outcome, totals, counts) = exchange(&[], &["--transport", "tcp"]);
+        assert_eq!(answered(&outcome), Some((Rcode::NoError, 1)));
+        assert_eq!((totals.sent, totals.success), (1, 1));
+        assert_eq!(counts.answered.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn answers_with_rcode() {
+        let (outcome, _, _) = exchange(&["--rcodes", "NXDOMAIN"], &[]);
+        assert_eq!(answered(&outcome), Some((Rcode::NxDomain, 0)));
+    }
+
+    #[test]
+    fn wrong_id_is_stray() {
+        let (outcome, totals, counts) = exchange(&["--wrong-id", "1"], &[]);
+        assert_eq!(outcome, WorkerStatus::Timeout(QType(1)));
+        assert_eq!((totals.timeout, totals.stray), (1, 1));
+        assert_eq!(counts.wrong_id.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn dropped_times_out() {
+        let (outcome, totals, counts) = exchange(&["--drop", "1"], &[]);
+        assert_eq!(outcome, WorkerStatus::Timeout(QType(1)));
+        assert_eq!((totals.sent, totals.timeout, totals.stray), (1, 1, 0));
+        assert_eq!(counts.dropped.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn shares_are_checked() {
+        assert!(Serve::try_parse_from(["serve", "--drop", "1.5"]).is_err());
+        assert!(Serve::try_parse_from(["serve", "--truncate", "nan"]).is_err());
+        assert!(Serve::try_parse_from(["serve", "--wrong-id", "-0.1"]).is_err());
+        assert!(Serve::try_parse_from(["serve", "--drop", "0.25"]).is_ok());
+    }
+}
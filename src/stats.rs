@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use hdrhistogram::Histogram;
+
+use crate::{
+    message::{QType, Rcode},
+    validate::{Check, Verdict},
+    WorkerStatus,
+};
+
+/// CPU time used by the whole process, both by the workers and by the
+/// thread collecting their results
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cpu {
+    pub user: Duration,
+    pub system: Duration,
+}
+
+impl Cpu {
+    #[cfg(unix)]
+    pub fn now() -> Cpu {
+        // SAFETY: getrusage only writes into `usage`
+        let usage = unsafe {
+            let mut usage = std::mem::zeroed();
+            libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+            usage
+        };
+        let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        Cpu {
+            user: time(usage.ru_utime),
+            system: time(usage.ru_stime),
+        }
+    }
+
+    /// Not measured on this platform
+    #[cfg(not(unix))]
+    pub fn now() -> Cpu {
+        Cpu::default()
+    }
+
+    /// The time used since `earlier`
+    pub fn since(&self, earlier: &Cpu) -> Cpu {
+        Cpu {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.user + self.system
+    }
+}
+
+/// Round-trip latency of successful queries, recorded in microseconds
+pub struct Latency {
+    hist: Histogram<u64>,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        // 1us .. 1h with 3 significant digits
+        Latency {
+            hist: Histogram::new_with_bounds(1, 3_600_000_000, 3).unwrap(),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.hist
+            .saturating_record(latency.as_micros().max(1) as u64);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.hist.len()
+    }
+
+    /// Names of the `points`
+    pub const POINTS: [&'static str; 7] = ["min", "mean", "p50", "p90", "p99", "p99.9", "max"];
+
+    /// Named summary points in milliseconds, or None without any samples
+    pub fn points(&self) -> Option<[(&'static str, f64); 7]> {
+        if self.hist.is_empty() {
+            return None;
+        }
+        let ms = |us: u64| us as f64 / 1000.0;
+        Some([
+            ("min", ms(self.hist.min())),
+            ("mean", self.hist.mean() / 1000.0),
+            ("p50", ms(self.hist.value_at_quantile(0.5))),
+            ("p90", ms(self.hist.value_at_quantile(0.9))),
+            ("p99", ms(self.hist.value_at_quantile(0.99))),
+            ("p99.9", ms(self.hist.value_at_quantile(0.999))),
+            ("max", ms(self.hist.max())),
+        ])
+    }
+
+    pub fn summary(&self) -> String {
+        match self.points() {
+            None => "no successful queries".to_string(),
+            Some(points) => points
+                .iter()
+                .map(|(name, ms)| format!("{}: {:.3}ms", name, ms))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+/// Outcomes of the queries of one type, for --record mixes
+pub struct TypeStats {
+    pub sent: u64,
+    pub success: u64,
+    pub timeout: u64,
+    pub failed: u64,
+    pub latency: Latency,
+}
+
+impl TypeStats {
+    pub fn new() -> Self {
+        TypeStats {
+            sent: 0,
+            success: 0,
+            timeout: 0,
+            failed: 0,
+            latency: Latency::new(),
+        }
+    }
+}
+
+/// Answers checked against --reference or --expected
+#[derive(Default)]
+pub struct Validation {
+    pub matched: u64,
+    pub missing: u64,
+    pub mismatch: u64,
+    pub unknown: u64,
+    pub ttl_anomalies: u64,
+}
+
+impl Validation {
+    fn record(&mut self, check: &Check) {
+        match check.verdict {
+            Verdict::Match => self.matched += 1,
+            Verdict::Missing => self.missing += 1,
+            Verdict::Mismatch => self.mismatch += 1,
+            Verdict::Unknown => self.unknown += 1,
+        }
+        self.ttl_anomalies += check.ttl_anomalies as u64;
+    }
+}
+
+/// How many answers had each part of the OPT record sent back
+#[derive(Default)]
+pub struct Echoes {
+    pub answers: u64,
+    pub opt: u64,
+    pub dnssec: u64,
+    pub ecs: u64,
+    pub cookie: u64,
+    pub nsid: u64,
+    pub padding: u64,
+}
+
+/// Everything counted from the worker status messages of a run
+pub struct Totals {
+    pub sent: u64,
+    pub success: u64,
+    pub timeout: u64,
+    pub failed: u64,
+    pub connect_failed: u64,
+    pub stray: u64,
+    /// Answers that came back with the TC bit set
+    pub truncated: u64,
+    /// Truncated queries answered by a retry over TCP
+    pub over_tcp: u64,
+    pub http_status: BTreeMap<u16, u64>,
+    pub rcodes: BTreeMap<Rcode, u64>,
+    pub latency: Latency,
+    pub by_type: BTreeMap<QType, TypeStats>,
+    pub connections: u64,
+    pub resumed: u64,
+    pub handshake: Latency,
+    pub validation: Validation,
+    pub echoes: Echoes,
+}
+
+impl Totals {
+    pub fn new() -> Self {
+        Totals {
+            sent: 0,
+            success: 0,
+            timeout: 0,
+            failed: 0,
+            connect_failed: 0,
+            stray: 0,
+            truncated: 0,
+            over_tcp: 0,
+            http_status: BTreeMap::new(),
+            rcodes: BTreeMap::new(),
+            latency: Latency::new(),
+            by_type: BTreeMap::new(),
+            connections: 0,
+            resumed: 0,
+            handshake: Latency::new(),
+            validation: Validation::default(),
+            echoes: Echoes::default(),
+        }
+    }
+
+    pub fn record(&mut self, status: &WorkerStatus) {
+        match *status {
+            WorkerStatus::Sent(qtype) => {
+                self.sent += 1;
+                self.of_type(qtype).sent += 1;
+            }
+            WorkerStatus::Success {
+                qtype,
+                rtt,
+                rcode,
+                over_tcp,
+                echo,
+                check,
+                ..
+            } => {
+                self.over_tcp += over_tcp as u64;
+                if let Some(echo) = echo {
+                    let echoes = &mut self.echoes;
+                    echoes.answers += 1;
+                    echoes.opt += echo.opt as u64;
+                    echoes.dnssec += echo.dnssec as u64;
+                    echoes.ecs += echo.ecs as u64;
+                    echoes.cookie += echo.cookie as u64;
+                    echoes.nsid += echo.nsid as u64;
+                    echoes.padding += echo.padding as u64;
+                }
+                if let Some(check) = check {
+                    self.validation.record(&check);
+                }
+                self.success += 1;
+                *self.rcodes.entry(rcode).or_insert(0) += 1;
+                self.latency.record(rtt);
+                let stats = self.of_type(qtype);
+                stats.success += 1;
+                stats.latency.record(rtt);
+            }
+            WorkerStatus::Truncated(_) => self.truncated += 1,
+            WorkerStatus::Timeout(qtype) => {
+                self.timeout += 1;
+                self.of_type(qtype).timeout += 1;
+            }
+            WorkerStatus::Failed(qtype) => {
+                self.failed += 1;
+                self.of_type(qtype).failed += 1;
+            }
+            WorkerStatus::Connected(ref setup) => {
+                self.connections += 1;
+                if setup.resumed {
+                    self.resumed += 1;
+                }
+                self.handshake.record(setup.time);
+            }
+            WorkerStatus::ConnectFailed(_) => self.connect_failed += 1,
+            WorkerStatus::HttpStatus(qtype, status) => {
+                *self.http_status.entry(status).or_insert(0) += 1;
+                self.of_type(qtype).failed += 1;
+            }
+            WorkerStatus::Checked(check) => self.validation.record(&check),
+            WorkerStatus::Stray => self.stray += 1,
+            WorkerStatus::Traced(ref trace) => self.record(&trace.outcome),
+            WorkerStatus::AllFinished => {}
+        }
+    }
+
+    fn of_type(&mut self, qtype: QType) -> &mut TypeStats {
+        self.by_type.entry(qtype).or_insert_with(TypeStats::new)
+    }
+}
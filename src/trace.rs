@@ -0,0 +1,82 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::{report, Args, WorkerStatus};
+
+/// A finished query as it is written to the --trace-file, one JSON object
+/// per line
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    /// When the query was (scheduled to be) sent
+    pub time: SystemTime,
+    /// Index of the worker that sent it
+    pub thread: u32,
+    pub id: u16,
+    pub qname: String,
+    /// Success, Timeout, Failed or HttpStatus
+    pub outcome: WorkerStatus,
+}
+
+/// Wraps the outcome of a query in a `Trace` when --trace-file is set
+pub fn wrap(
+    tracing: bool,
+    thread: u32,
+    id: u16,
+    qname: &str,
+    start: Instant,
+    outcome: WorkerStatus,
+) -> WorkerStatus {
+    if !tracing {
+        return outcome;
+    }
+    WorkerStatus::Traced(Box::new(Trace {
+        time: SystemTime::now() - start.elapsed(),
+        thread,
+        id,
+        qname: qname.to_string(),
+        outcome,
+    }))
+}
+
+impl Trace {
+    pub fn line(&self, args: &Args) -> String {
+        let (qtype, outcome) = match self.outcome {
+            WorkerStatus::Success { qtype, .. } => (qtype, "success"),
+            WorkerStatus::Timeout(qtype) => (qtype, "timeout"),
+            WorkerStatus::HttpStatus(qtype, _) => (qtype, "http_status"),
+            WorkerStatus::Failed(qtype) => (qtype, "failed"),
+            _ => unreachable!(),
+        };
+        let mut line = json!({
+            "time": self.time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            "thread": self.thread,
+            "id": self.id,
+            "server": args.server.to_string(),
+            "qname": self.qname,
+            "qtype": qtype.to_string(),
+            "transport": report::name(&args.transport),
+            "latency_ms": null,
+            "rcode": null,
+            "answers": null,
+            "outcome": outcome,
+        });
+        match self.outcome {
+            WorkerStatus::Success {
+                rtt,
+                rcode,
+                answers,
+                over_tcp,
+                ..
+            } => {
+                line["latency_ms"] = json!(rtt.as_secs_f64() * 1000.0);
+                line["over_tcp"] = json!(over_tcp);
+                line["rcode"] = json!(rcode.to_string());
+                line["answers"] = json!(answers);
+            }
+            WorkerStatus::HttpStatus(_, status) => line["http_status"] = json!(status),
+            _ => {}
+        }
+        line.to_string()
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use rustls::{ClientConnection, HandshakeKind};
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(target_os = "linux")]
+use crate::mmsg;
+use crate::{doh::Doh, tls::Tls, Args};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Transport {
+    Udp,
+    /// Length-prefixed messages over one reused connection (RFC 7766)
+    Tcp,
+    /// DNS over TLS (RFC 7858), TCP framing inside a TLS session
+    Tls,
+    /// DNS over HTTPS (RFC 8484) on HTTP/2
+    Doh,
+}
+
+/// How workers drive their sockets
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Io {
+    /// Blocking system calls, from one thread per worker, or two with
+    /// --inflight
+    Threads,
+    /// One io_uring per worker that keeps the sends and receives of all
+    /// outstanding queries queued at once (Linux, udp and tcp only)
+    Uring,
+}
+
+/// A path to the server over which whole DNS messages are exchanged.
+///
+/// A connection lost while receiving is reported as
+/// `ErrorKind::ConnectionAborted`; every query still waiting on it is lost.
+/// A UDP datagram from any other address than the server's is received as
+/// a `Foreign` error.
+pub trait Conn: Send {
+    /// Makes sure the connection is established before sending. Returns
+    /// how the setup went if a new connection had to be made.
+    fn connect(&mut self) -> io::Result<Option<Setup>>;
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()>;
+
+    /// Receives one message into `buf`, returning its length. Gives up with
+    /// a timeout error once `deadline` has passed.
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize>;
+
+    /// Sends several messages, returning how many went out before the first
+    /// failure. Sends them one by one unless the transport can do better.
+    fn send_batch(&mut self, msgs: &[Vec<u8>]) -> io::Result<usize> {
+        for (sent, msg) in msgs.iter().enumerate() {
+            if let Err(e) = self.send(msg) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(msgs.len())
+    }
+
+    /// Receives up to one message per buffer, waiting for the first one
+    /// only, and returns how many there are. The length of each goes to
+    /// `lens`, or None for a UDP datagram from a foreign address.
+    fn recv_batch(
+        &mut self,
+        bufs: &mut [Vec<u8>],
+        lens: &mut [Option<usize>],
+        deadline: Instant,
+    ) -> io::Result<usize> {
+        lens[0] = Some(self.recv(&mut bufs[0], deadline)?);
+        Ok(1)
+    }
+
+    /// Another handle to the same connection, so that one thread can send
+    /// while another receives
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>>;
+}
+
+/// Cost of establishing a connection, kept apart from query latency
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setup {
+    /// TCP connect plus TLS handshake, if any
+    pub time: Duration,
+    /// The TLS session was resumed rather than fully negotiated
+    pub resumed: bool,
+}
+
+/// Local address of the sockets of one worker
+#[derive(Clone, Debug)]
+pub struct Local {
+    ip: IpAddr,
+    ports: Option<(u16, u16)>,
+    /// Where this worker starts looking for a free port in the range
+    offset: u32,
+}
+
+impl Local {
+    /// The --source IPs are handed out to the workers in turn, so that they
+    /// look like that many clients. Without any, the wildcard address of
+    /// the server's family is used.
+    pub fn new(args: &Args, worker: u32) -> Self {
+        let ip = match args.source.as_slice() {
+            [] if args.server.is_ipv4() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            [] => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            sources => sources[worker as usize % sources.len()],
+        };
+        Local {
+            ip,
+            ports: args.source_port,
+            offset: worker / args.source.len().max(1) as u32,
+        }
+    }
+
+    /// Calls `bind` with the first address that works: any port without
+    /// --source-port, otherwise the ports of the range in turn
+    pub fn bind<T>(&self, bind: impl Fn(SocketAddr) -> io::Result<T>) -> io::Result<T> {
+        let Some((low, high)) = self.ports else {
+            return bind(SocketAddr::new(self.ip, 0));
+        };
+        let size = (high - low) as u32 + 1;
+        let mut last = Err(ErrorKind::AddrInUse.into());
+        for i in 0..size {
+            let port = low + ((self.offset + i) % size) as u16;
+            last = bind(SocketAddr::new(self.ip, port));
+            if last.is_ok() {
+                break;
+            }
+        }
+        last
+    }
+}
+
+pub fn open(args: &Args, tls: Option<&Tls>, local: Local) -> Box<dyn Conn> {
+    match args.transport {
+        Transport::Udp => {
+            // Not connected, so that datagrams from elsewhere are seen and
+            // counted instead of silently dropped by the kernel
+            Box::new(Udp {
+                socket: local.bind(UdpSocket::bind).unwrap(),
+                server: args.server,
+            })
+        }
+        Transport::Tcp | Transport::Tls => Box::new(Tcp::new(
+            args.server,
+            local,
+            Duration::from_millis(args.timeout),
+            tls.map(|tls| (tls.config.clone(), tls.name.clone())),
+        )),
+        Transport::Doh => Box::new(Doh::new(args, tls.unwrap(), local)),
+    }
+}
+
+/// A plain TCP connection to `server`, for retrying truncated UDP answers
+pub fn tcp(server: SocketAddr, local: Local, timeout: Duration) -> Box<dyn Conn> {
+    Box::new(Tcp::new(server, local, timeout, None))
+}
+
+/// Opens a TCP connection to `server` from the worker's local address
+pub fn dial(server: SocketAddr, local: &Local, timeout: Duration) -> io::Result<TcpStream> {
+    let socket = local.bind(|addr| {
+        let socket = Socket::new(
+            Domain::for_address(server),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // A port of the range may still be in TIME_WAIT from an earlier
+        // connection
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    })?;
+    socket.connect_timeout(&server.into(), timeout)?;
+    let tcp = TcpStream::from(socket);
+    tcp.set_nodelay(true)?;
+    Ok(tcp)
+}
+
+/// Read timeouts show up as `WouldBlock` on Unix and `TimedOut` on Windows
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// A datagram from some address other than the server
+#[derive(Debug)]
+pub struct Foreign(pub SocketAddr);
+
+impl fmt::Display for Foreign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "datagram from unexpected address {}", self.0)
+    }
+}
+
+impl Error for Foreign {}
+
+pub fn is_foreign(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Foreign>())
+}
+
+/// Time left until `deadline`, or a timeout error if it has passed
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+struct Udp {
+    socket: UdpSocket,
+    server: SocketAddr,
+}
+
+impl Conn for Udp {
+    fn connect(&mut self) -> io::Result<Option<Setup>> {
+        Ok(None)
+    }
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.socket.send_to(msg, self.server).map(|_| ())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.socket.set_read_timeout(Some(remaining(deadline)?))?;
+        let (len, from) = self.socket.recv_from(buf)?;
+        if from != self.server {
+            return Err(io::Error::other(Foreign(from)));
+        }
+        Ok(len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self, msgs: &[Vec<u8>]) -> io::Result<usize> {
+        mmsg::send(&self.socket, self.server, msgs)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(
+        &mut self,
+        bufs: &mut [Vec<u8>],
+        lens: &mut [Option<usize>],
+        deadline: Instant,
+    ) -> io::Result<usize> {
+        self.socket.set_read_timeout(Some(remaining(deadline)?))?;
+        let mut received = [(0, None); mmsg::MAX_BATCH];
+        let count = mmsg::recv(&self.socket, bufs, &mut received)?;
+        for (len, (received, from)) in lens.iter_mut().zip(&received[..count]) {
+            *len = (*from == Some(self.server)).then_some(*received);
+        }
+        Ok(count)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(Udp {
+            socket: self.socket.try_clone()?,
+            server: self.server,
+        }))
+    }
+}
+
+/// The current connection of a worker, shared by all handles to it. The
+/// generation changes on every reconnect so that stale handles notice.
+struct Slot {
+    generation: u64,
+    stream: Option<Stream>,
+}
+
+/// One TCP connection, optionally carrying a TLS session. The session sits
+/// behind a lock so the sender and receiver can use it from their own
+/// threads; socket I/O itself happens outside the lock.
+struct Stream {
+    tcp: TcpStream,
+    tls: Option<Arc<Mutex<ClientConnection>>>,
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Stream {
+            tcp: self.tcp.try_clone()?,
+            tls: self.tls.clone(),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &self.tls {
+            None => self.tcp.write_all(data),
+            Some(session) => {
+                let mut records = Vec::new();
+                {
+                    let mut session = session.lock().unwrap();
+                    session.writer().write_all(data)?;
+                    while session.wants_write() {
+                        session.write_tls(&mut records)?;
+                    }
+                }
+                self.tcp.write_all(&records)
+            }
+        }
+    }
+
+    /// Appends whatever payload arrives next to `buf`. Returns false once the
+    /// server has closed the connection.
+    fn read(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        let n = self.tcp.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        let Some(session) = &self.tls else {
+            buf.extend_from_slice(&chunk[..n]);
+            return Ok(true);
+        };
+
+        let mut records = Vec::new();
+        let open = {
+            let mut session = session.lock().unwrap();
+            let mut input = &chunk[..n];
+            while !input.is_empty() {
+                session.read_tls(&mut input)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            let open = loop {
+                let mut plain = [0; 4096];
+                match session.reader().read(&mut plain) {
+                    Ok(0) => break false,
+                    Ok(n) => buf.extend_from_slice(&plain[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                    Err(e) => return Err(e),
+                }
+            };
+            while session.wants_write() {
+                session.write_tls(&mut records)?;
+            }
+            open
+        };
+        if !records.is_empty() {
+            self.tcp.write_all(&records)?;
+        }
+        Ok(open)
+    }
+}
+
+/// A TCP (or TLS) connection that is kept open across queries and
+/// re-established by the next `connect` after it breaks
+struct Tcp {
+    server: SocketAddr,
+    local: Local,
+    /// Used for connection setup and for writes
+    timeout: Duration,
+    tls: Option<(
+        Arc<rustls::ClientConfig>,
+        rustls::pki_types::ServerName<'static>,
+    )>,
+    shared: Arc<Mutex<Slot>>,
+    /// This handle's clone of the shared stream and its generation
+    stream: Option<(u64, Stream)>,
+    /// Payload bytes received but not yet returned as a whole message
+    buf: Vec<u8>,
+}
+
+impl Tcp {
+    fn new(
+        server: SocketAddr,
+        local: Local,
+        timeout: Duration,
+        tls: Option<(
+            Arc<rustls::ClientConfig>,
+            rustls::pki_types::ServerName<'static>,
+        )>,
+    ) -> Tcp {
+        Tcp {
+            server,
+            local,
+            timeout,
+            tls,
+            shared: Arc::new(Mutex::new(Slot {
+                generation: 0,
+                stream: None,
+            })),
+            stream: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Picks up the current shared stream if this handle's one is stale
+    fn refresh(&mut self, slot: &Slot) -> io::Result<()> {
+        if self.stream.as_ref().map(|(g, _)| *g) != Some(slot.generation) {
+            self.stream = None;
+            self.buf.clear();
+            if let Some(stream) = &slot.stream {
+                self.stream = Some((slot.generation, stream.try_clone()?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the connection for every handle, unless it was already replaced
+    fn broken(&mut self) {
+        if let Some((generation, _)) = self.stream.take() {
+            let mut slot = self.shared.lock().unwrap();
+            if slot.generation == generation {
+                slot.stream = None;
+            }
+        }
+        self.buf.clear();
+    }
+
+    fn establish(&self) -> io::Result<(Stream, Setup)> {
+        let start = Instant::now();
+        let mut tcp = dial(self.server, &self.local, self.timeout)?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+
+        let Some((config, name)) = &self.tls else {
+            let setup = Setup {
+                time: start.elapsed(),
+                resumed: false,
+            };
+            return Ok((Stream { tcp, tls: None }, setup));
+        };
+        let mut session = ClientConnection::new(config.clone(), name.clone())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        while session.is_handshaking() {
+            session.complete_io(&mut tcp).map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock => {
+                    io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")
+                }
+                _ => e,
+            })?;
+        }
+        let setup = Setup {
+            time: start.elapsed(),
+            resumed: session.handshake_kind() == Some(HandshakeKind::Resumed),
+        };
+        let stream = Stream {
+            tcp,
+            tls: Some(Arc::new(Mutex::new(session))),
+        };
+        Ok((stream, setup))
+    }
+}
+
+impl Conn for Tcp {
+    fn connect(&mut self) -> io::Result<Option<Setup>> {
+        let shared = self.shared.clone();
+        let mut slot = shared.lock().unwrap();
+        let mut setup = None;
+        if slot.stream.is_none() {
+            let (stream, done) = self.establish()?;
+            slot.generation += 1;
+            slot.stream = Some(stream);
+            setup = Some(done);
+        }
+        self.refresh(&slot)?;
+        Ok(setup)
+    }
+
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let Some((_, stream)) = self.stream.as_mut() else {
+            return Err(ErrorKind::NotConnected.into());
+        };
+        let mut framed = Vec::with_capacity(msg.len() + 2);
+        framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        framed.extend_from_slice(msg);
+        let result = stream.write(&framed);
+        if result.is_err() {
+            self.broken();
+        }
+        result
+    }
+
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        let shared = self.shared.clone();
+        self.refresh(&shared.lock().unwrap())?;
+        if self.stream.is_none() {
+            // Nothing to read until the sending side reconnects
+            thread::sleep(remaining(deadline)?);
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        loop {
+            if self.buf.len() >= 2 {
+                let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+                if self.buf.len() >= 2 + len {
+                    let n = len.min(buf.len());
+                    buf[..n].copy_from_slice(&self.buf[2..2 + n]);
+                    self.buf.drain(..2 + len);
+                    return Ok(n);
+                }
+            }
+
+            let (_, stream) = self.stream.as_mut().unwrap();
+            stream.tcp.set_read_timeout(Some(remaining(deadline)?))?;
+            match stream.read(&mut self.buf) {
+                Ok(true) => {}
+                Err(e) if is_timeout(&e) => return Err(e),
+                Ok(false) | Err(_) => {
+                    self.broken();
+                    return Err(ErrorKind::ConnectionAborted.into());
+                }
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Conn>> {
+        Ok(Box::new(Tcp {
+            server: self.server,
+            local: self.local.clone(),
+            timeout: self.timeout,
+            tls: self.tls.clone(),
+            shared: self.shared.clone(),
+            stream: None,
+            buf: Vec::new(),
+        }))
+    }
+}
@@ -0,0 +1,687 @@
+use std::{
+    collections::VecDeque,
+    ffi::c_void,
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream, UdpSocket},
+    os::fd::AsRawFd,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use io_uring::{
+    opcode, squeue,
+    types::{Fd, SubmitArgs, Timespec},
+    IoUring,
+};
+use socket2::{SockAddr, SockAddrStorage};
+
+use crate::{
+    ids::Ids,
+    message::QType,
+    pipeline::{self, Window},
+    progress,
+    transport::{self, Local, Setup, Transport},
+    workload::Workload,
+    Args, Handler, WorkerStatus,
+};
+
+/// Submission queue entries of each ring; a full queue is submitted early
+const ENTRIES: u32 = 256;
+
+/// Receives kept queued on a UDP socket at most
+const RECVS: usize = 64;
+
+/// How often the window is looked at again while --tcp-fallback retries are
+/// under way, as nothing in the ring marks their end
+const RETRY_TICK: Duration = Duration::from_millis(10);
+
+/// What an operation is, in the upper half of its user_data; the lower half
+/// is its slot
+const SEND: u64 = 0;
+const RECV: u64 = 1;
+const CANCEL: u64 = 2;
+const TIMEOUT: u64 = 3;
+
+fn user_data(kind: u64, slot: usize) -> u64 {
+    kind << 32 | slot as u64
+}
+
+/// What the completions of a socket's operations amount to
+enum Event<'a> {
+    /// A message from the server
+    Packet(&'a [u8]),
+    /// A UDP datagram from some other address
+    Stray,
+    /// The query went out in full
+    Sent(QType),
+    /// The query could not be sent
+    SendFailed(u16, QType),
+    /// The TCP connection is gone, and every query still waiting on it
+    Lost,
+}
+
+/// The socket of one worker, with the operations it has in the ring
+trait Socket {
+    /// Queues the first operations, before any query
+    fn start(&mut self, _ring: &mut IoUring) {}
+
+    /// Makes sure the connection is established before sending, as
+    /// `crate::connect` does. Returns false if no connection could be made.
+    fn connect(&mut self, ring: &mut IoUring, handler: &Handler) -> bool;
+
+    /// Queues the query that `write` encodes
+    fn send(&mut self, ring: &mut IoUring, id: u16, qtype: QType, write: impl FnOnce(&mut Vec<u8>));
+
+    /// Takes in the completion of one of its operations
+    fn complete(
+        &mut self,
+        ring: &mut IoUring,
+        user_data: u64,
+        result: i32,
+        on: &mut dyn FnMut(Event),
+    );
+
+    /// Whether a lost connection is still being torn down, which keeps
+    /// new queries back
+    fn busy(&self) -> bool {
+        false
+    }
+
+    /// Whether some query has not gone out in full yet
+    fn sending(&self) -> bool;
+
+    /// Cancels the receives, once there is nothing left to wait for
+    fn close(&mut self, ring: &mut IoUring);
+
+    /// Operations in the ring that have not completed yet
+    fn outstanding(&self) -> usize;
+}
+
+/// Queues an operation, submitting the queued ones first if there is no
+/// room left.
+///
+/// SAFETY: whatever the entry points to must stay alive and in place until
+/// its completion has been reaped
+unsafe fn push(ring: &mut IoUring, entry: squeue::Entry) {
+    while ring.submission().push(&entry).is_err() {
+        ring.submit().unwrap();
+    }
+}
+
+/// Submits the queued operations and waits for a completion, or until
+/// `deadline`. Kernels before 5.11 cannot be given a deadline to wait for;
+/// there a timeout operation that also completes on the next completion
+/// stands in.
+fn wait(ring: &mut IoUring, deadline: Option<Instant>) {
+    let ext_arg = ring.params().is_feature_ext_arg();
+    let result = match deadline {
+        Some(deadline) => {
+            let timespec = Timespec::from(deadline.saturating_duration_since(Instant::now()));
+            match ext_arg {
+                true => {
+                    let args = SubmitArgs::new().timespec(&timespec);
+                    ring.submitter().submit_with_args(1, &args)
+                }
+                false => {
+                    let entry = opcode::Timeout::new(&timespec)
+                        .count(1)
+                        .build()
+                        .user_data(user_data(TIMEOUT, 0));
+                    // SAFETY: the kernel copies the timespec when the entry
+                    // is submitted, which is below
+                    unsafe { push(ring, entry) };
+                    ring.submit_and_wait(1)
+                }
+            }
+        }
+        None => ring.submit_and_wait(1),
+    };
+    if let Err(e) = result {
+        // ETIME just means the deadline came first
+        assert!(
+            matches!(e.raw_os_error(), Some(libc::ETIME | libc::EINTR)),
+            "io_uring_enter: {}",
+            e
+        );
+    }
+}
+
+/// Starts a worker that keeps up to `--inflight` queries outstanding on one
+/// socket or connection from a single thread, through an io_uring. The ring
+/// and UDP socket are set up before, so that a failure ends the run right
+/// away as with `transport::open`, rather than leave a worker that never
+/// finishes.
+pub fn spawn(args: &Args, local: Local, workload: Workload, handler: Handler) -> JoinHandle<()> {
+    let ring = IoUring::new(ENTRIES)
+        .unwrap_or_else(|e| panic!("cannot set up an io_uring for --io uring: {}", e));
+    let udp = (args.transport == Transport::Udp).then(|| local.bind(UdpSocket::bind).unwrap());
+    let limit = args.inflight as usize;
+    let ids = Ids::new(args.random_ids);
+    let server = args.server;
+    thread::spawn(move || match udp {
+        Some(socket) => {
+            let udp = Udp::new(socket, server, limit.min(RECVS));
+            drive(ring, udp, limit, ids, workload, handler)
+        }
+        None => {
+            let tcp = Tcp::new(server, local, handler.timeout);
+            drive(ring, tcp, limit, ids, workload, handler)
+        }
+    })
+}
+
+fn drive(
+    mut ring: IoUring,
+    mut socket: impl Socket,
+    limit: usize,
+    mut ids: Ids,
+    mut workload: Workload,
+    handler: Handler,
+) {
+    let mut rng = rand::thread_rng();
+    let mut window = Window::new();
+    let retries = pipeline::Retries::start(&handler, &mut window, || ());
+    let mut completions = Vec::new();
+    let qclass = handler.encoder.qclass;
+    let on = |event: Event, window: &mut Window| match event {
+        Event::Packet(packet) => match window.answered(packet, qclass) {
+            Some((id, query, v)) => pipeline::complete(&handler, id, query, &v, retries.as_ref()),
+            None => handler.stray(),
+        },
+        Event::Stray => handler.stray(),
+        Event::Sent(qtype) => handler
+            .tx
+            .send((WorkerStatus::Sent(qtype), thread::current().id()))
+            .unwrap(),
+        Event::SendFailed(id, qtype) => {
+            // Unless it has already timed out
+            if let Some(query) = window.pending.remove(&id) {
+                pipeline::finish(&handler, id, query, WorkerStatus::Failed(qtype));
+            }
+        }
+        Event::Lost => {
+            for (id, query) in window.drain() {
+                let status = WorkerStatus::Failed(query.qtype);
+                pipeline::finish(&handler, id, query, status);
+            }
+        }
+    };
+
+    socket.start(&mut ring);
+    let mut done = false;
+    loop {
+        while !done && !socket.busy() && window.outstanding() < limit && workload.ready() {
+            let Some((start, qname, qtype, index)) = workload.next() else {
+                done = true;
+                break;
+            };
+            if !socket.connect(&mut ring, &handler) {
+                continue;
+            }
+            if handler.debug >= 2 {
+                let line = format!("select domain: {} {}", qname, qtype);
+                progress(&line, handler.to_stderr);
+            }
+            let id = window.add(&mut ids, &mut rng, start, qtype, qname);
+            let encoder = &handler.encoder;
+            socket.send(&mut ring, id, qtype, |wire| {
+                encoder.write(wire, id, qname, qtype, index, &mut rng)
+            });
+        }
+        if done && window.pending.is_empty() && !socket.sending() {
+            break;
+        }
+
+        // Until something completes, the oldest query times out, the next
+        // one is due or a retry may have freed its place
+        let open = !done && !socket.busy() && window.outstanding() < limit;
+        let due = if open { workload.due() } else { None };
+        let expiry = window.oldest().map(|sent| sent + handler.timeout);
+        let retry = (window.retrying() > 0).then(|| Instant::now() + RETRY_TICK);
+        wait(&mut ring, due.into_iter().chain(expiry).chain(retry).min());
+
+        completions.extend(ring.completion().map(|cqe| (cqe.user_data(), cqe.result())));
+        for (user_data, result) in completions.drain(..) {
+            socket.complete(&mut ring, user_data, result, &mut |event| {
+                on(event, &mut window)
+            });
+        }
+        for (id, query) in window.expire(handler.timeout) {
+            let status = WorkerStatus::Timeout(query.qtype);
+            pipeline::finish(&handler, id, query, status);
+        }
+    }
+
+    // The buffers must outlive every operation that uses them
+    socket.close(&mut ring);
+    while socket.outstanding() > 0 {
+        wait(&mut ring, None);
+        completions.extend(ring.completion().map(|cqe| (cqe.user_data(), cqe.result())));
+        for (user_data, result) in completions.drain(..) {
+            socket.complete(&mut ring, user_data, result, &mut |event| {
+                on(event, &mut window)
+            });
+        }
+    }
+    if let Some(retries) = retries {
+        retries.finish();
+    }
+    handler
+        .tx
+        .send((WorkerStatus::AllFinished, thread::current().id()))
+        .unwrap();
+}
+
+/// A datagram buffer with the message header the kernel reads it through or
+/// writes it through. It must stay in place while in the ring.
+struct Datagram {
+    buf: Vec<u8>,
+    iovec: libc::iovec,
+    header: libc::msghdr,
+    /// Where a received datagram came from
+    from: SockAddrStorage,
+}
+
+impl Datagram {
+    fn new(size: usize) -> Datagram {
+        Datagram {
+            buf: vec![0; size],
+            // SAFETY: all zeros is a valid iovec and msghdr
+            iovec: unsafe { mem::zeroed() },
+            header: unsafe { mem::zeroed() },
+            from: SockAddrStorage::zeroed(),
+        }
+    }
+
+    /// Points the header at the whole buffer, and at `to` for sending or at
+    /// `from` for receiving
+    fn header(&mut self, to: Option<&SockAddr>) -> *mut libc::msghdr {
+        self.iovec.iov_base = self.buf.as_mut_ptr() as *mut c_void;
+        self.iovec.iov_len = self.buf.len();
+        match to {
+            Some(to) => {
+                self.header.msg_name = to.as_ptr() as *mut c_void;
+                self.header.msg_namelen = to.len();
+            }
+            None => {
+                self.header.msg_name = &mut self.from as *mut SockAddrStorage as *mut c_void;
+                self.header.msg_namelen = self.from.size_of();
+            }
+        }
+        self.header.msg_iov = &mut self.iovec;
+        self.header.msg_iovlen = 1;
+        &mut self.header
+    }
+}
+
+/// An unconnected UDP socket, as with the threads, with every receive
+/// buffer queued and one send per query
+struct Udp {
+    socket: UdpSocket,
+    server: SockAddr,
+    /// Never resized, so the datagrams stay in place
+    recvs: Vec<Datagram>,
+    /// How many of `recvs` are in the ring
+    receiving: usize,
+    /// Buffers of sends, in the ring or free, with their queries; boxed
+    /// since more are added as needed
+    sends: Vec<(Box<Datagram>, u16, QType)>,
+    free: Vec<usize>,
+    closing: bool,
+}
+
+impl Udp {
+    fn new(socket: UdpSocket, server: SocketAddr, recvs: usize) -> Udp {
+        Udp {
+            socket,
+            server: server.into(),
+            recvs: (0..recvs).map(|_| Datagram::new(65535)).collect(),
+            receiving: 0,
+            sends: Vec::new(),
+            free: Vec::new(),
+            closing: false,
+        }
+    }
+
+    fn recv(&mut self, ring: &mut IoUring, slot: usize) {
+        let header = self.recvs[slot].header(None);
+        let entry = opcode::RecvMsg::new(Fd(self.socket.as_raw_fd()), header)
+            .build()
+            .user_data(user_data(RECV, slot));
+        // SAFETY: the datagram is only dropped once no operation is
+        // outstanding
+        unsafe { push(ring, entry) };
+        self.receiving += 1;
+    }
+}
+
+impl Socket for Udp {
+    fn start(&mut self, ring: &mut IoUring) {
+        for slot in 0..self.recvs.len() {
+            self.recv(ring, slot);
+        }
+    }
+
+    fn connect(&mut self, _ring: &mut IoUring, _handler: &Handler) -> bool {
+        true
+    }
+
+    fn send(
+        &mut self,
+        ring: &mut IoUring,
+        id: u16,
+        qtype: QType,
+        write: impl FnOnce(&mut Vec<u8>),
+    ) {
+        let slot = self.free.pop().unwrap_or_else(|| {
+            self.sends.push((Box::new(Datagram::new(0)), 0, qtype));
+            self.sends.len() - 1
+        });
+        let (datagram, query_id, query_type) = &mut self.sends[slot];
+        (*query_id, *query_type) = (id, qtype);
+        write(&mut datagram.buf);
+        let header = datagram.header(Some(&self.server));
+        let entry = opcode::SendMsg::new(Fd(self.socket.as_raw_fd()), header)
+            .build()
+            .user_data(user_data(SEND, slot));
+        // SAFETY: as for receives; the server address lives as long
+        unsafe { push(ring, entry) };
+    }
+
+    fn complete(
+        &mut self,
+        ring: &mut IoUring,
+        user_data: u64,
+        result: i32,
+        on: &mut dyn FnMut(Event),
+    ) {
+        let slot = user_data as u32 as usize;
+        match user_data >> 32 {
+            SEND => {
+                self.free.push(slot);
+                let (_, id, qtype) = self.sends[slot];
+                match result {
+                    0.. => on(Event::Sent(qtype)),
+                    _ => on(Event::SendFailed(id, qtype)),
+                }
+            }
+            RECV => {
+                self.receiving -= 1;
+                if result == -libc::ECANCELED {
+                    return;
+                }
+                // Errors that cannot be tied to a single query are ignored,
+                // as with the threads
+                if let Ok(len) = usize::try_from(result) {
+                    let datagram = &mut self.recvs[slot];
+                    let from = mem::replace(&mut datagram.from, SockAddrStorage::zeroed());
+                    // SAFETY: the kernel filled in the address and its length
+                    let from = unsafe { SockAddr::new(from, datagram.header.msg_namelen) };
+                    match from.as_socket() == self.server.as_socket() {
+                        true => on(Event::Packet(&datagram.buf[..len])),
+                        false => on(Event::Stray),
+                    }
+                }
+                if !self.closing {
+                    self.recv(ring, slot);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn sending(&self) -> bool {
+        self.free.len() < self.sends.len()
+    }
+
+    fn close(&mut self, ring: &mut IoUring) {
+        self.closing = true;
+        for slot in 0..self.recvs.len() {
+            let entry = opcode::AsyncCancel::new(user_data(RECV, slot))
+                .build()
+                .user_data(user_data(CANCEL, slot));
+            // SAFETY: a cancel points to nothing
+            unsafe { push(ring, entry) };
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.receiving + self.sends.len() - self.free.len()
+    }
+}
+
+/// A TCP connection with one write of the framed queries and one read in
+/// the ring at a time
+struct Tcp {
+    server: SocketAddr,
+    local: Local,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    /// The bytes being written, and how many of them are written
+    writing: Vec<u8>,
+    written: usize,
+    /// Whether the write is in the ring
+    sending: bool,
+    /// Framed queries that go out once the current write is done
+    queued: Vec<u8>,
+    /// Where each query not written in full yet ends in the stream, for
+    /// telling when it is sent
+    ends: VecDeque<(u64, QType)>,
+    /// Bytes queued and written on the connection so far
+    total_queued: u64,
+    total_written: u64,
+    /// A scratch buffer for encoding
+    wire: Vec<u8>,
+    /// Read bytes that do not make up a whole message yet
+    reading: Vec<u8>,
+    filled: usize,
+    receiving: bool,
+    /// The connection is gone but some operation on it is still in the ring
+    lost: bool,
+    closing: bool,
+}
+
+impl Tcp {
+    fn new(server: SocketAddr, local: Local, timeout: Duration) -> Tcp {
+        Tcp {
+            server,
+            local,
+            timeout,
+            stream: None,
+            writing: Vec::new(),
+            written: 0,
+            sending: false,
+            queued: Vec::new(),
+            ends: VecDeque::new(),
+            total_queued: 0,
+            total_written: 0,
+            wire: Vec::new(),
+            // Room for a whole message whatever is left of the one before
+            reading: vec![0; 2 * (2 + 65535)],
+            filled: 0,
+            receiving: false,
+            lost: false,
+            closing: false,
+        }
+    }
+
+    fn fd(&self) -> Fd {
+        Fd(self.stream.as_ref().unwrap().as_raw_fd())
+    }
+
+    /// Starts writing the queued queries unless a write is in the ring
+    fn flush(&mut self, ring: &mut IoUring) {
+        if self.sending || self.queued.is_empty() {
+            return;
+        }
+        mem::swap(&mut self.writing, &mut self.queued);
+        self.queued.clear();
+        self.written = 0;
+        self.write(ring);
+    }
+
+    fn write(&mut self, ring: &mut IoUring) {
+        let rest = &self.writing[self.written..];
+        let entry = opcode::Send::new(self.fd(), rest.as_ptr(), rest.len() as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+            .user_data(user_data(SEND, 0));
+        // SAFETY: `writing` is not touched while the write is in the ring
+        unsafe { push(ring, entry) };
+        self.sending = true;
+    }
+
+    fn read(&mut self, ring: &mut IoUring) {
+        let fd = self.fd();
+        let rest = &mut self.reading[self.filled..];
+        let entry = opcode::Recv::new(fd, rest.as_mut_ptr(), rest.len() as u32)
+            .build()
+            .user_data(user_data(RECV, 0));
+        // SAFETY: `reading` is not touched while the read is in the ring
+        unsafe { push(ring, entry) };
+        self.receiving = true;
+    }
+
+    /// Gives up the connection; it is closed once its operations are done
+    fn lose(&mut self, on: &mut dyn FnMut(Event)) {
+        if self.lost {
+            return;
+        }
+        self.lost = true;
+        // Completes whatever is still in the ring
+        let _ = self.stream.as_ref().unwrap().shutdown(Shutdown::Both);
+        self.queued.clear();
+        self.ends.clear();
+        on(Event::Lost);
+    }
+}
+
+impl Socket for Tcp {
+    fn connect(&mut self, ring: &mut IoUring, handler: &Handler) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        let began = Instant::now();
+        let status = match transport::dial(self.server, &self.local, self.timeout) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.filled = 0;
+                self.read(ring);
+                WorkerStatus::Connected(Setup {
+                    time: began.elapsed(),
+                    resumed: false,
+                })
+            }
+            Err(e) => WorkerStatus::ConnectFailed(e.to_string()),
+        };
+        let connected = matches!(status, WorkerStatus::Connected(_));
+        handler.tx.send((status, thread::current().id())).unwrap();
+        connected
+    }
+
+    fn send(
+        &mut self,
+        ring: &mut IoUring,
+        _id: u16,
+        qtype: QType,
+        write: impl FnOnce(&mut Vec<u8>),
+    ) {
+        write(&mut self.wire);
+        self.queued
+            .extend_from_slice(&(self.wire.len() as u16).to_be_bytes());
+        self.queued.extend_from_slice(&self.wire);
+        self.total_queued += 2 + self.wire.len() as u64;
+        self.ends.push_back((self.total_queued, qtype));
+        self.flush(ring);
+    }
+
+    fn complete(
+        &mut self,
+        ring: &mut IoUring,
+        user_data: u64,
+        result: i32,
+        on: &mut dyn FnMut(Event),
+    ) {
+        match user_data >> 32 {
+            SEND => {
+                self.sending = false;
+                match usize::try_from(result) {
+                    Ok(written) if written > 0 && !self.lost => {
+                        self.written += written;
+                        self.total_written += written as u64;
+                        while let Some(&(end, qtype)) = self.ends.front() {
+                            if end > self.total_written {
+                                break;
+                            }
+                            self.ends.pop_front();
+                            on(Event::Sent(qtype));
+                        }
+                        match self.written < self.writing.len() {
+                            true => self.write(ring),
+                            false => self.flush(ring),
+                        }
+                    }
+                    _ => self.lose(on),
+                }
+            }
+            RECV => {
+                self.receiving = false;
+                match usize::try_from(result) {
+                    Ok(read) if read > 0 && !self.lost => {
+                        self.filled += read;
+                        let mut start = 0;
+                        while self.filled - start >= 2 {
+                            let len =
+                                u16::from_be_bytes([self.reading[start], self.reading[start + 1]])
+                                    as usize;
+                            if self.filled - start - 2 < len {
+                                break;
+                            }
+                            on(Event::Packet(&self.reading[start + 2..start + 2 + len]));
+                            start += 2 + len;
+                        }
+                        self.reading.copy_within(start..self.filled, 0);
+                        self.filled -= start;
+                        if !self.closing {
+                            self.read(ring);
+                        }
+                    }
+                    _ if result == -libc::ECANCELED && self.closing => {}
+                    _ => self.lose(on),
+                }
+            }
+            _ => {}
+        }
+        if self.lost && !self.sending && !self.receiving {
+            self.stream = None;
+            self.lost = false;
+            // What was not written is not going to be
+            self.writing.clear();
+            self.written = 0;
+            self.total_written = self.total_queued;
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.lost
+    }
+
+    fn sending(&self) -> bool {
+        self.sending || !self.queued.is_empty()
+    }
+
+    fn close(&mut self, ring: &mut IoUring) {
+        self.closing = true;
+        if self.receiving {
+            let entry = opcode::AsyncCancel::new(user_data(RECV, 0))
+                .build()
+                .user_data(user_data(CANCEL, 0));
+            // SAFETY: a cancel points to nothing
+            unsafe { push(ring, entry) };
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.sending as usize + self.receiving as usize
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    encode::Encoder,
+    ids::Ids,
+    message::{Message, QType},
+    pipeline::Window,
+    Args, WorkerStatus,
+};
+
+/// How an answer compares with the expected one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Match,
+    /// Only some of the expected records are there
+    Missing,
+    /// There are records that were not expected
+    Mismatch,
+    /// Nothing is known about the right answer
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Check {
+    pub verdict: Verdict,
+    /// Records with a higher TTL than the expected one. Caches only ever
+    /// count TTLs down, so this hints at a resolver making them up.
+    pub ttl_anomalies: u16,
+}
+
+/// Name, type and data of a record, with the name in lower case
+type Key = (String, QType, String);
+
+/// The records of a right answer and their TTLs
+type Answer = HashMap<Key, u32>;
+
+/// A question, with the name as in `Key`
+type Question = (String, QType);
+
+/// The right answers known so far, shared with the thread that looks them up
+type Expected = Arc<Mutex<HashMap<Question, Arc<Answer>>>>;
+
+/// The right answers, from an --expected file or looked up on the
+/// --reference server. Each question is looked up there only once, by a
+/// thread of its own, so that the workers never wait for the reference:
+/// answers that arrive before the right one are checked once it is known.
+pub struct Validator {
+    expected: Expected,
+    /// Answers to check once the reference has answered, with --reference
+    lookups: Option<Sender<Lookup>>,
+}
+
+/// An answer whose question has not been looked up on the reference yet
+struct Lookup {
+    qname: String,
+    qtype: QType,
+    got: Vec<(Key, u32)>,
+}
+
+impl Validator {
+    /// With --reference, also starts the thread that asks it; that thread
+    /// sends the checks it makes to `tx` and finishes once every
+    /// `Validator` handle is gone.
+    pub fn new(args: &Args, tx: &Sender<(WorkerStatus, ThreadId)>) -> Option<Self> {
+        let mut expected = HashMap::new();
+        if let Some(file) = &args.expected {
+            for line in BufReader::new(File::open(file).unwrap()).lines() {
+                let line = line.unwrap();
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let v: Value = serde_json::from_str(&line).unwrap();
+                let qtype: QType = v["qtype"].as_str().unwrap().parse().unwrap();
+                let records = v["records"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|record| parse_record(record.as_str().unwrap()))
+                    .collect();
+                expected.insert(
+                    (question(v["qname"].as_str().unwrap()), qtype),
+                    Arc::new(records),
+                );
+            }
+        } else if args.reference.is_none() {
+            return None;
+        }
+        let expected = Arc::new(Mutex::new(expected));
+        let lookups = args.reference.map(|reference| {
+            let (lookups, rx) = channel();
+            let resolver = Resolver {
+                reference,
+                timeout: Duration::from_millis(args.timeout),
+                // Queries to the reference are written like the benchmark's,
+                // as class, DNSSEC or client subnet options change the answers
+                encoder: Encoder::new(args),
+                expected: expected.clone(),
+                tx: tx.clone(),
+            };
+            thread::spawn(move || resolver.run(rx));
+            lookups
+        });
+        Some(Validator { expected, lookups })
+    }
+
+    /// Checks the answer `msg` if the right one is known. Otherwise it is
+    /// checked later, with the check sent as `WorkerStatus::Checked`, and
+    /// this returns None.
+    pub fn check(&self, qname: &str, qtype: QType, msg: &Message) -> Option<Check> {
+        let cached = self
+            .expected
+            .lock()
+            .unwrap()
+            .get(&(question(qname), qtype))
+            .cloned();
+        match (cached, &self.lookups) {
+            (Some(expected), _) => Some(compare(&expected, &records(msg))),
+            (None, Some(lookups)) => {
+                let lookup = Lookup {
+                    qname: qname.to_string(),
+                    qtype,
+                    got: records(msg),
+                };
+                // Only fails once the resolver is gone, at the very end
+                let _ = lookups.send(lookup);
+                None
+            }
+            (None, None) => Some(Check {
+                verdict: Verdict::Unknown,
+                ttl_anomalies: 0,
+            }),
+        }
+    }
+}
+
+/// How the records `got` compare with the right answer
+fn compare(expected: &Answer, got: &[(Key, u32)]) -> Check {
+    let verdict = if got.iter().any(|(key, _)| !expected.contains_key(key)) {
+        Verdict::Mismatch
+    } else if expected
+        .keys()
+        .any(|key| !got.iter().any(|(got, _)| got == key))
+    {
+        Verdict::Missing
+    } else {
+        Verdict::Match
+    };
+    let ttl_anomalies = got
+        .iter()
+        .filter(|(key, ttl)| expected.get(key).is_some_and(|expected| ttl > expected))
+        .count() as u16;
+    Check {
+        verdict,
+        ttl_anomalies,
+    }
+}
+
+/// Most questions asked of the reference at once
+const LOOKUPS: usize = 256;
+
+/// Asks the reference server the questions of the answers it is sent, over
+/// UDP and several at a time
+struct Resolver {
+    reference: SocketAddr,
+    timeout: Duration,
+    encoder: Encoder,
+    expected: Expected,
+    tx: Sender<(WorkerStatus, ThreadId)>,
+}
+
+impl Resolver {
+    fn run(self, rx: Receiver<Lookup>) {
+        let any = match self.reference.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0)).unwrap();
+        socket.connect(self.reference).unwrap();
+        let tick = self.timeout.min(Duration::from_millis(10));
+        socket.set_read_timeout(Some(tick)).unwrap();
+
+        let mut rng = rand::thread_rng();
+        let mut ids = Ids::new(true);
+        let mut window = Window::new();
+        // The answers to check against each question being looked up
+        let mut waiting: HashMap<Question, Vec<Vec<(Key, u32)>>> = HashMap::new();
+        let mut wire = Vec::new();
+        let mut packet = vec![0; 65535];
+        let mut open = true;
+        while open || !window.pending.is_empty() {
+            // Waits for answers to check only while no question is out
+            while open && window.pending.len() < LOOKUPS {
+                let lookup = match window.pending.is_empty() {
+                    true => rx.recv().map_err(|_| TryRecvError::Disconnected),
+                    false => rx.try_recv(),
+                };
+                let lookup = match lookup {
+                    Ok(lookup) => lookup,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        open = false;
+                        break;
+                    }
+                };
+                let key = (question(&lookup.qname), lookup.qtype);
+                let cached = self.expected.lock().unwrap().get(&key).cloned();
+                if let Some(expected) = cached {
+                    self.checked(compare(&expected, &lookup.got));
+                    continue;
+                }
+                if let Some(answers) = waiting.get_mut(&key) {
+                    answers.push(lookup.got);
+                    continue;
+                }
+                let (qname, qtype) = (&lookup.qname, lookup.qtype);
+                let id = window.add(&mut ids, &mut rng, Instant::now(), qtype, qname);
+                self.encoder
+                    .write(&mut wire, id, qname, qtype, None, &mut rng);
+                // A failed send just times out
+                let _ = socket.send(&wire);
+                waiting.insert(key, vec![lookup.got]);
+            }
+
+            if let Ok(len) = socket.recv(&mut packet) {
+                if let Some((_, query, msg)) = window.answered(&packet[..len], self.encoder.qclass)
+                {
+                    let key = (question(&query.qname), query.qtype);
+                    let expected: Arc<Answer> = Arc::new(records(&msg).into_iter().collect());
+                    self.expected
+                        .lock()
+                        .unwrap()
+                        .insert(key.clone(), expected.clone());
+                    for got in waiting.remove(&key).unwrap_or_default() {
+                        self.checked(compare(&expected, &got));
+                    }
+                }
+            }
+            // A question the reference did not answer in time may be asked
+            // again by a later answer
+            for (_, query) in window.expire(self.timeout) {
+                let key = (question(&query.qname), query.qtype);
+                for _ in waiting.remove(&key).unwrap_or_default() {
+                    self.checked(Check {
+                        verdict: Verdict::Unknown,
+                        ttl_anomalies: 0,
+                    });
+                }
+            }
+        }
+        self.tx
+            .send((WorkerStatus::AllFinished, thread::current().id()))
+            .unwrap();
+    }
+
+    fn checked(&self, check: Check) {
+        self.tx
+            .send((WorkerStatus::Checked(check), thread::current().id()))
+            .unwrap();
+    }
+}
+
+fn records(msg: &Message) -> Vec<(Key, u32)> {
+    msg.answers
+        .iter()
+        .map(|record| {
+            let key = (question(&record.name), record.rtype, data(&record.data()));
+            (key, record.ttl)
+        })
+        .collect()
+}
+
+/// A name as used for lookups: lower case, without the trailing dot
+fn question(qname: &str) -> String {
+    qname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Record data as compared: names in it are in lower case. Nothing else in
+/// the presentation form is case sensitive, as opaque data is in hex.
+fn data(data: &str) -> String {
+    data.to_ascii_lowercase()
+}
+
+/// Parses a record as printed with -v 2: `name ttl type data`
+fn parse_record(s: &str) -> (Key, u32) {
+    let mut fields = s.split_whitespace();
+    let name = question(fields.next().unwrap());
+    let ttl = fields.next().unwrap().parse().unwrap();
+    let rtype = fields.next().unwrap().parse().unwrap();
+    let data = data(&fields.collect::<Vec<_>>().join(" "));
+    ((name, rtype, data), ttl)
+}
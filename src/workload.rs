@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng};
+use serde_json::Value;
+
+use crate::{message::QType, mix::Mix, rate::Pacer};
+
+/// The queries of one worker
+pub struct Workload {
+    pub source: Source,
+    /// End of a --duration run; no query is sent after it
+    pub until: Option<Instant>,
+    /// Seeded from --seed, so that every server gets the same queries
+    pub rng: StdRng,
+}
+
+/// Where the queries of a worker come from
+pub enum Source {
+    /// Queries for random domains, with types drawn from the mix and paced
+    /// by --qps if set. `left` counts down --number unless the run is
+    /// limited by --duration instead
+    Random {
+        domains: Arc<Vec<String>>,
+        mix: Mix,
+        pacer: Option<Pacer>,
+        left: Option<u32>,
+    },
+    /// The queries of a --replay trace, sent at their recorded offsets from
+    /// `start`
+    Replay {
+        queries: Vec<Replayed>,
+        next: usize,
+        start: Instant,
+    },
+}
+
+pub struct Replayed {
+    offset: Duration,
+    qname: String,
+    qtype: QType,
+}
+
+impl Workload {
+    /// Waits until the next query is due and returns the time it should
+    /// have been sent at, its name, its type and its number among the
+    /// pre-encoded `Packets` if it is one of them. None once all are sent.
+    pub fn next(&mut self) -> Option<(Instant, &str, QType, Option<usize>)> {
+        let rng = &mut self.rng;
+        if self.until.is_some_and(|until| Instant::now() >= until) {
+            return None;
+        }
+        let next = match &mut self.source {
+            Source::Random {
+                domains,
+                mix,
+                pacer,
+                left,
+            } => {
+                if let Some(left) = left {
+                    if *left == 0 {
+                        return None;
+                    }
+                    *left -= 1;
+                }
+                let start = match pacer.as_mut() {
+                    Some(pacer) => pacer.wait(rng),
+                    None => Instant::now(),
+                };
+                let domain = rng.gen_range(0..domains.len());
+                let qtype = mix.pick(rng);
+                (
+                    start,
+                    domains[domain].as_str(),
+                    mix.types[qtype],
+                    Some(domain * mix.types.len() + qtype),
+                )
+            }
+            Source::Replay {
+                queries,
+                next,
+                start,
+            } => {
+                let query = queries.get(*next)?;
+                *next += 1;
+                let due = *start + query.offset;
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                (due, query.qname.as_str(), query.qtype, None)
+            }
+        };
+        match self.until {
+            // The pacer or the replay timetable slept past the end
+            Some(until) if next.0 >= until => None,
+            _ => Some(next),
+        }
+    }
+
+    /// Whether the next query is due already, so that it can go out in the
+    /// same batch as the previous one
+    pub fn ready(&self) -> bool {
+        self.due().is_none_or(|due| due <= Instant::now())
+    }
+
+    /// When the next query is scheduled; None if it is not scheduled at all
+    /// but sent as soon as possible, or if there are no more
+    pub fn due(&self) -> Option<Instant> {
+        match &self.source {
+            Source::Random { pacer, .. } => pacer.as_ref().map(Pacer::due),
+            Source::Replay {
+                queries,
+                next,
+                start,
+            } => queries.get(*next).map(|query| *start + query.offset),
+        }
+    }
+}
+
+/// Reads a trace written by --trace-file and deals its queries out to
+/// `threads` workers. Queries of one recorded thread stay on one worker, and
+/// offsets are relative to the first query of the whole trace. With
+/// `server`, only the queries that went to it are taken.
+pub fn replay(
+    file: &str,
+    threads: u32,
+    server: Option<SocketAddr>,
+) -> Result<Vec<Vec<Replayed>>, String> {
+    let unreadable = |e: std::io::Error| format!("cannot read {}: {}", file, e);
+    let mut lines = Vec::new();
+    let mut servers = BTreeSet::new();
+    for (n, line) in BufReader::new(File::open(file).map_err(unreadable)?)
+        .lines()
+        .enumerate()
+    {
+        let line = line.map_err(unreadable)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let malformed = || format!("{} line {} is not a --trace-file line", file, n + 1);
+        let v: Value = serde_json::from_str(&line).map_err(|_| malformed())?;
+        // Traces from before the field was added are of a single server
+        if let Some(recorded) = v["server"].as_str() {
+            let recorded: SocketAddr = recorded.parse().map_err(|_| malformed())?;
+            servers.insert(recorded);
+            if server.is_some_and(|server| server != recorded) {
+                continue;
+            }
+        }
+        let qtype: QType = v["qtype"]
+            .as_str()
+            .and_then(|qtype| qtype.parse().ok())
+            .ok_or_else(malformed)?;
+        lines.push((
+            v["time"].as_f64().ok_or_else(malformed)?,
+            v["thread"].as_u64().ok_or_else(malformed)?,
+            v["qname"].as_str().ok_or_else(malformed)?.to_string(),
+            qtype,
+        ));
+    }
+
+    match server {
+        None if servers.len() > 1 => {
+            return Err(format!(
+                "{} has queries for {} servers; pick one with --replay-server",
+                file,
+                servers.len()
+            ))
+        }
+        Some(server) if !servers.is_empty() && !servers.contains(&server) => {
+            return Err(format!("{} has no queries for {}", file, server))
+        }
+        _ => {}
+    }
+
+    // Lines are written as queries finish, not as they are sent
+    lines.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let first = lines.first().map_or(0.0, |line| line.0);
+    let mut workers: Vec<Vec<Replayed>> = (0..threads).map(|_| Vec::new()).collect();
+    for (time, thread, qname, qtype) in lines {
+        workers[(thread % threads as u64) as usize].push(Replayed {
+            offset: Duration::from_secs_f64(time - first),
+            qname,
+            qtype,
+        });
+    }
+    Ok(workers)
+}